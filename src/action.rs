@@ -0,0 +1,371 @@
+use crate::reflink::reflink;
+use crate::trash::trash;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// What is done with a duplicate file
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Delete the duplicate
+    Delete,
+    /// Replace the duplicate with a hard link to the reference file
+    Hardlink,
+    /// Replace the duplicate with a symbolic link to the reference file
+    Symlink,
+    /// Make the duplicate share its storage with the reference file (Linux only)
+    Reflink,
+    /// Move the duplicate into a quarantine directory
+    Move,
+    /// Move the duplicate to the trash
+    Trash,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.to_possible_value().unwrap().get_name())
+    }
+}
+
+/// Options controlling how duplicate files are handled
+#[derive(clap::Args, Clone, Debug, Serialize, Deserialize)]
+pub struct ActionOptions {
+    /// What is done with duplicate files
+    #[arg(
+        short('a'),
+        long("action"),
+        value_enum,
+        default_value_t = Action::Delete
+    )]
+    pub action: Action,
+    /// Create symbolic links relative to the duplicate location instead of absolute ones
+    #[arg(long("relative"))]
+    pub relative: bool,
+    /// Directory duplicates are moved into by the move action
+    #[arg(
+        long("quarantine"),
+        value_name = "DIR",
+        required_if_eq("action", "move")
+    )]
+    #[serde(with = "crate::escape::option_path", default)]
+    pub quarantine: Option<PathBuf>,
+}
+
+impl Action {
+    /// Checks whether the action needs the reference file to exist
+    pub fn needs_reference(self) -> bool {
+        matches!(self, Self::Hardlink | Self::Symlink | Self::Reflink)
+    }
+}
+
+impl ActionOptions {
+    /// Applies the action to a duplicate of a reference file
+    ///
+    /// # Arguments
+    /// * `target` - A path to the duplicate file
+    /// * `reference` - A path to the reference file
+    /// * `root` - A path to the deduplicated directory containing `target`
+    /// * `before` - Called right before the duplicate is changed, with its new
+    ///   location if the action moves it. The action is not applied if it fails.
+    ///
+    /// # Returns
+    /// * A new location of the duplicate, if the action moved it
+    pub fn apply(
+        &self,
+        target: &Path,
+        reference: &Path,
+        root: &Path,
+        before: impl FnOnce(Option<&Path>) -> io::Result<()>,
+    ) -> io::Result<Option<PathBuf>> {
+        match self.action {
+            Action::Delete => {
+                before(None)?;
+                fs::remove_file(target)?
+            }
+            Action::Hardlink => {
+                before(None)?;
+                replace_with(target, |tmp| fs::hard_link(reference, tmp))?
+            }
+            Action::Symlink => {
+                let link = self.symlink_target(target, reference)?;
+                before(None)?;
+                replace_with(target, |tmp| symlink(&link, tmp))?
+            }
+            Action::Reflink => {
+                before(None)?;
+                reflink(target, reference)?
+            }
+            Action::Move => {
+                let destination = self.move_destination(target, root);
+                fs::create_dir_all(destination.parent().unwrap())?;
+                let destination = claim_unused_path(&destination)?;
+                before(Some(&destination))
+                    .and_then(|()| move_file(target, &destination))
+                    .inspect_err(|_| {
+                        let _ = fs::remove_file(&destination);
+                    })?;
+                return Ok(Some(destination));
+            }
+            Action::Trash => return trash(target, |trashed| before(Some(trashed))).map(Some),
+        }
+        Ok(None)
+    }
+
+    /// Returns the path a symbolic link to `reference` replacing `target` points to
+    pub fn symlink_target(&self, target: &Path, reference: &Path) -> io::Result<PathBuf> {
+        if self.relative {
+            let target_dir = absolute_parent(target)?;
+            let reference_dir = absolute_parent(reference)?;
+            Ok(relative_path(&target_dir, &reference_dir).join(reference.file_name().unwrap()))
+        } else {
+            std::path::absolute(reference)
+        }
+    }
+
+    /// Returns the path in the quarantine directory `target` is moved to, ignoring collisions
+    pub fn move_destination(&self, target: &Path, root: &Path) -> PathBuf {
+        let quarantine = self.quarantine.as_ref().unwrap();
+        quarantine.join(target.strip_prefix(root).unwrap())
+    }
+}
+
+/// Returns `path` if it is not taken yet, or `path` with a numeric suffix which is not taken
+pub fn unused_path(path: &Path, is_taken: impl Fn(&Path) -> bool) -> PathBuf {
+    let mut index = 0;
+    while is_taken(&numbered_path(path, index)) {
+        index += 1;
+    }
+    numbered_path(path, index)
+}
+
+/// Atomically creates an empty file at `path`, or at `path` with a numeric suffix if it is taken
+///
+/// Unlike checking with [`unused_path`] first, this never picks a path
+/// which another process takes in the meantime, so the file can then be
+/// safely replaced with `fs::rename`.
+///
+/// # Returns
+/// * The path of the created file
+pub fn claim_unused_path(path: &Path) -> io::Result<PathBuf> {
+    let mut index = 0;
+    loop {
+        let candidate = numbered_path(path, index);
+        match fs::File::options()
+            .write(true)
+            .create_new(true)
+            .open(&candidate)
+        {
+            Ok(_) => return Ok(candidate),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => index += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Returns `path` with `.<index>` appended to its file name, or `path` itself for index 0
+fn numbered_path(path: &Path, index: usize) -> PathBuf {
+    if index == 0 {
+        return path.to_owned();
+    }
+    let mut file_name = path.file_name().unwrap().to_owned();
+    file_name.push(format!(".{index}"));
+    path.with_file_name(file_name)
+}
+
+/// Moves a file, copying it if the destination is on another filesystem
+pub fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            let modified = from.metadata()?.modified()?;
+            fs::copy(from, to)?;
+            fs::File::open(to)?.set_modified(modified)?;
+            fs::remove_file(from)
+        }
+        result => result,
+    }
+}
+
+/// Returns a canonical path of a directory containing `path`
+fn absolute_parent(path: &Path) -> io::Result<PathBuf> {
+    let path = std::path::absolute(path)?;
+    path.parent().unwrap().canonicalize()
+}
+
+/// Returns a path to `to` relative to `from`
+///
+/// Both paths must be absolute and must not contain `.` or `..` components.
+fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let mut from = from.components().peekable();
+    let mut to = to.components().peekable();
+    while from.peek().is_some() && from.peek() == to.peek() {
+        from.next();
+        to.next();
+    }
+    let mut path: PathBuf = from.map(|_| Component::ParentDir).collect();
+    path.extend(to);
+    path
+}
+
+#[cfg(unix)]
+fn symlink(original: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+#[cfg(windows)]
+fn symlink(original: &Path, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(original, link)
+}
+
+/// Returns a path for a temporary file next to `path` which does not exist yet
+pub fn temporary_path(path: &Path) -> PathBuf {
+    let mut index = 0;
+    loop {
+        let mut file_name = OsString::from(".");
+        file_name.push(path.file_name().unwrap());
+        file_name.push(format!(".dedup{index}"));
+        let tmp = path.with_file_name(file_name);
+        if !tmp.exists() && !tmp.is_symlink() {
+            return tmp;
+        }
+        index += 1;
+    }
+}
+
+/// Atomically replaces a file with a new one
+///
+/// The new file is created by `create` at a temporary path in the same
+/// directory and then renamed over `path`, so `path` never disappears.
+pub fn replace_with(path: &Path, create: impl FnOnce(&Path) -> io::Result<()>) -> io::Result<()> {
+    let tmp = temporary_path(path);
+    create(&tmp)?;
+    fs::rename(&tmp, path).inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn options(action: Action) -> ActionOptions {
+        ActionOptions {
+            action,
+            relative: false,
+            quarantine: None,
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hardlink() {
+        use std::os::unix::fs::MetadataExt;
+
+        let tmp = TempDir::new("test_hardlink").unwrap();
+        let reference = tmp.path().join("reference");
+        let target = tmp.path().join("target");
+        fs::write(&reference, "content").unwrap();
+        fs::write(&target, "content").unwrap();
+
+        options(Action::Hardlink)
+            .apply(&target, &reference, tmp.path(), |_| Ok(()))
+            .unwrap();
+        assert_eq!(
+            target.metadata().unwrap().ino(),
+            reference.metadata().unwrap().ino()
+        );
+        assert_eq!(fs::read_dir(tmp.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_relative_path() {
+        assert_eq!(
+            relative_path(Path::new("/a/b/c"), Path::new("/a/d")),
+            Path::new("../../d")
+        );
+        assert_eq!(
+            relative_path(Path::new("/a"), Path::new("/a/b")),
+            Path::new("b")
+        );
+        assert_eq!(
+            relative_path(Path::new("/a"), Path::new("/a")),
+            Path::new("")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink() {
+        let tmp = TempDir::new("test_symlink").unwrap();
+        let reference = tmp.path().join("ref").join("file");
+        let target = tmp.path().join("target").join("dir").join("file");
+        fs::create_dir(tmp.path().join("ref")).unwrap();
+        fs::create_dir_all(target.parent().unwrap()).unwrap();
+        fs::write(&reference, "content").unwrap();
+        fs::write(&target, "content").unwrap();
+
+        let options = ActionOptions {
+            relative: true,
+            ..options(Action::Symlink)
+        };
+        options
+            .apply(&target, &reference, tmp.path(), |_| Ok(()))
+            .unwrap();
+        assert_eq!(fs::read_link(&target).unwrap(), Path::new("../../ref/file"));
+        assert_eq!(fs::read_to_string(&target).unwrap(), "content");
+    }
+
+    #[test]
+    fn test_move() {
+        let tmp = TempDir::new("test_move").unwrap();
+        let reference = tmp.path().join("reference");
+        let root = tmp.path().join("target");
+        let quarantine = tmp.path().join("quarantine");
+        fs::create_dir_all(root.join("dir")).unwrap();
+        fs::create_dir_all(quarantine.join("dir")).unwrap();
+        fs::write(&reference, "content").unwrap();
+        fs::write(root.join("dir").join("file"), "content").unwrap();
+        fs::write(quarantine.join("dir").join("file"), "other").unwrap();
+
+        let options = ActionOptions {
+            quarantine: Some(quarantine.clone()),
+            ..options(Action::Move)
+        };
+        let destination = options
+            .apply(
+                &root.join("dir").join("file"),
+                &reference,
+                &root,
+                |_| Ok(()),
+            )
+            .unwrap();
+        assert_eq!(destination, Some(quarantine.join("dir").join("file.1")));
+        assert!(!root.join("dir").join("file").exists());
+        assert_eq!(
+            fs::read_to_string(quarantine.join("dir").join("file")).unwrap(),
+            "other"
+        );
+        assert_eq!(
+            fs::read_to_string(quarantine.join("dir").join("file.1")).unwrap(),
+            "content"
+        );
+
+        fs::write(root.join("dir").join("file"), "content").unwrap();
+        symlink(Path::new("missing"), &quarantine.join("dir").join("file.2")).unwrap();
+        let destination = options
+            .apply(
+                &root.join("dir").join("file"),
+                &reference,
+                &root,
+                |_| Ok(()),
+            )
+            .unwrap();
+        assert_eq!(destination, Some(quarantine.join("dir").join("file.3")));
+        assert!(quarantine.join("dir").join("file.2").is_symlink());
+    }
+}
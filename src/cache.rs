@@ -0,0 +1,227 @@
+use crate::action::replace_with;
+use crate::hash::{parse_digest, Algorithm, Digest};
+use crate::state::FileId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, Metadata};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Metadata identifying a version of a file
+///
+/// Any change of the file content changes its modification or status
+/// change time, so hashes are valid as long as the stamp is the same.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stamp {
+    #[serde(flatten)]
+    id: FileId,
+    size: u64,
+    /// Modification time as seconds and nanoseconds
+    modified: (i64, i64),
+    /// Status change time as seconds and nanoseconds
+    changed: (i64, i64),
+}
+
+impl Stamp {
+    /// Returns a stamp of a file, where the platform provides all its parts
+    #[cfg(unix)]
+    pub fn of(meta: &Metadata) -> Option<Self> {
+        use std::os::unix::fs::MetadataExt;
+        Some(Self {
+            id: FileId::of(meta)?,
+            size: meta.len(),
+            modified: (meta.mtime(), meta.mtime_nsec()),
+            changed: (meta.ctime(), meta.ctime_nsec()),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn of(_meta: &Metadata) -> Option<Self> {
+        None
+    }
+}
+
+/// Hashes of a version of a file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CachedHashes {
+    pub partial: Option<Digest>,
+    pub full: Option<Digest>,
+}
+
+/// A line of the cache file
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    #[serde(flatten)]
+    stamp: Stamp,
+    /// Hash algorithm of both hashes
+    #[serde(default)]
+    algorithm: Algorithm,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    partial: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    full: Option<String>,
+}
+
+/// Hashes of a file kept in the cache
+struct Cached {
+    stamp: Stamp,
+    hashes: CachedHashes,
+    /// Whether the hashes were looked up or stored since the cache was opened
+    used: bool,
+}
+
+/// Content hashes of files persisted across runs
+///
+/// Hashes are stored by device and inode number and by hash algorithm
+/// together with the size, modification time and status change time of
+/// the file, and are discarded as soon as any of them changes. Hashes
+/// which are not used during a run are not saved, so hashes of deleted
+/// files do not accumulate.
+pub struct HashCache {
+    path: PathBuf,
+    entries: Mutex<HashMap<(FileId, Algorithm), Cached>>,
+}
+
+impl HashCache {
+    /// Reads a cache file, or creates an empty cache if it does not exist yet
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut entries = HashMap::new();
+        match File::open(path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let entry: Entry = serde_json::from_str(&line?)?;
+                    let hashes = CachedHashes {
+                        partial: entry.partial.as_deref().map(parse_digest).transpose()?,
+                        full: entry.full.as_deref().map(parse_digest).transpose()?,
+                    };
+                    let cached = Cached {
+                        stamp: entry.stamp,
+                        hashes,
+                        used: false,
+                    };
+                    entries.insert((entry.stamp.id, entry.algorithm), cached);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(Self {
+            path: path.to_owned(),
+            entries: Mutex::new(entries),
+        })
+    }
+
+    /// Returns hashes of a file, if they are known for its current version
+    pub fn lookup(&self, stamp: &Stamp, algorithm: Algorithm) -> Option<CachedHashes> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get_mut(&(stamp.id, algorithm)) {
+            Some(cached) if cached.stamp == *stamp => {
+                cached.used = true;
+                Some(cached.hashes)
+            }
+            _ => None,
+        }
+    }
+
+    /// Stores hashes of a file, discarding hashes of its other versions
+    pub fn store(
+        &self,
+        stamp: &Stamp,
+        algorithm: Algorithm,
+        partial: Option<Digest>,
+        full: Option<Digest>,
+    ) {
+        let mut entries = self.entries.lock().unwrap();
+        let empty = CachedHashes {
+            partial: None,
+            full: None,
+        };
+        let cached = entries.entry((stamp.id, algorithm)).or_insert(Cached {
+            stamp: *stamp,
+            hashes: empty,
+            used: true,
+        });
+        if cached.stamp != *stamp {
+            cached.stamp = *stamp;
+            cached.hashes = empty;
+        }
+        cached.used = true;
+        cached.hashes.partial = partial.or(cached.hashes.partial);
+        cached.hashes.full = full.or(cached.hashes.full);
+    }
+
+    /// Atomically replaces the cache file with the hashes used since the cache was opened
+    pub fn save(&self) -> io::Result<()> {
+        let entries = self.entries.lock().unwrap();
+        let mut sorted: Vec<_> = entries.iter().filter(|(_, cached)| cached.used).collect();
+        sorted.sort_by_key(|((id, algorithm), _)| (id.device, id.inode, *algorithm));
+        replace_with(&self.path, |tmp| {
+            let mut writer = BufWriter::new(File::create(tmp)?);
+            for ((_, algorithm), cached) in sorted {
+                let entry = Entry {
+                    stamp: cached.stamp,
+                    algorithm: *algorithm,
+                    partial: cached.hashes.partial.map(|hash| hash.to_string()),
+                    full: cached.hashes.full.map(|hash| hash.to_string()),
+                };
+                serde_json::to_writer(&mut writer, &entry)?;
+                writeln!(writer)?;
+            }
+            writer.flush()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hash_cache() {
+        let tmp = TempDir::new("test_hash_cache").unwrap();
+        let cache_path = tmp.path().join("cache");
+        let path = tmp.path().join("file");
+        fs::write(&path, "content").unwrap();
+        let stamp = Stamp::of(&path.metadata().unwrap()).unwrap();
+        let hash = Algorithm::Blake3.hash_file(&path, None).unwrap();
+        let sha256 = Algorithm::Sha256.hash_file(&path, None).unwrap();
+
+        let cache = HashCache::open(&cache_path).unwrap();
+        assert_eq!(cache.lookup(&stamp, Algorithm::Blake3), None);
+        cache.store(&stamp, Algorithm::Blake3, Some(hash), None);
+        cache.store(&stamp, Algorithm::Sha256, None, Some(sha256));
+        cache.save().unwrap();
+
+        let cache = HashCache::open(&cache_path).unwrap();
+        let expected = CachedHashes {
+            partial: Some(hash),
+            full: None,
+        };
+        assert_eq!(cache.lookup(&stamp, Algorithm::Blake3), Some(expected));
+        let expected = CachedHashes {
+            partial: None,
+            full: Some(sha256),
+        };
+        assert_eq!(cache.lookup(&stamp, Algorithm::Sha256), Some(expected));
+        assert_eq!(cache.lookup(&stamp, Algorithm::Xxh3), None);
+        cache.save().unwrap();
+
+        // Only the BLAKE3 and SHA-256 hashes were looked up before saving
+        let cache = HashCache::open(&cache_path).unwrap();
+        assert_eq!(cache.entries.lock().unwrap().len(), 2);
+        cache.save().unwrap();
+        let cache = HashCache::open(&cache_path).unwrap();
+        assert_eq!(cache.lookup(&stamp, Algorithm::Blake3), None);
+
+        fs::write(&path, "changed").unwrap();
+        let new_stamp = Stamp::of(&path.metadata().unwrap()).unwrap();
+        assert_eq!(cache.lookup(&new_stamp, Algorithm::Blake3), None);
+        cache.store(&new_stamp, Algorithm::Blake3, None, Some(hash));
+        assert_eq!(cache.lookup(&stamp, Algorithm::Blake3), None);
+    }
+}
@@ -0,0 +1,149 @@
+//! Lossless encoding of paths as strings
+//!
+//! Valid UTF-8 is kept as is, except for backslashes which are doubled.
+//! Bytes which are not valid UTF-8 are written as `\xHH`.
+
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+/// Encodes an OS string as a Unicode string
+#[cfg(unix)]
+pub fn escape(s: &OsStr) -> String {
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut escaped = String::with_capacity(s.len());
+    for chunk in s.as_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if c == '\\' {
+                escaped.push_str("\\\\");
+            } else {
+                escaped.push(c);
+            }
+        }
+        for byte in chunk.invalid() {
+            escaped.push_str(&format!("\\x{byte:02x}"));
+        }
+    }
+    escaped
+}
+
+/// Encodes an OS string as a Unicode string
+///
+/// OS strings which are not valid Unicode are not supported on this platform.
+#[cfg(not(unix))]
+pub fn escape(s: &OsStr) -> String {
+    s.to_string_lossy().replace('\\', "\\\\")
+}
+
+/// Decodes a string produced by `escape`
+///
+/// The `\t`, `\n` and `\r` escape sequences are decoded as well, since they
+/// are used in formats which cannot contain these characters.
+/// Returns `None` if the string contains an invalid escape sequence.
+pub fn unescape(s: &str) -> Option<OsString> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+        match rest {
+            [b'\\', tail @ ..] => {
+                bytes.push(b'\\');
+                rest = tail;
+            }
+            [b't', tail @ ..] => {
+                bytes.push(b'\t');
+                rest = tail;
+            }
+            [b'n', tail @ ..] => {
+                bytes.push(b'\n');
+                rest = tail;
+            }
+            [b'r', tail @ ..] => {
+                bytes.push(b'\r');
+                rest = tail;
+            }
+            [b'x', hi, lo, tail @ ..] if hi.is_ascii_hexdigit() && lo.is_ascii_hexdigit() => {
+                let hex = [*hi, *lo];
+                bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).unwrap(), 16).unwrap());
+                rest = tail;
+            }
+            _ => return None,
+        }
+    }
+    bytes_to_os_string(bytes)
+}
+
+#[cfg(unix)]
+fn bytes_to_os_string(bytes: Vec<u8>) -> Option<OsString> {
+    use std::os::unix::ffi::OsStringExt;
+    Some(OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn bytes_to_os_string(bytes: Vec<u8>) -> Option<OsString> {
+    String::from_utf8(bytes).ok().map(OsString::from)
+}
+
+/// Serializes paths as escaped strings, for use with `#[serde(with = "...")]`
+pub mod path {
+    use super::*;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&escape(path.as_os_str()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        unescape(&s)
+            .map(PathBuf::from)
+            .ok_or_else(|| de::Error::custom(format!("invalid escaped path: {s:?}")))
+    }
+}
+
+/// Serializes optional paths as escaped strings, for use with `#[serde(with = "...")]`
+pub mod option_path {
+    use super::*;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        path: &Option<PathBuf>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        path.as_ref()
+            .map(|path| escape(path.as_os_str()))
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<PathBuf>, D::Error> {
+        #[derive(Deserialize)]
+        struct Wrapper(#[serde(with = "super::path")] PathBuf);
+        let path = Option::<Wrapper>::deserialize(deserializer)?;
+        Ok(path.map(|Wrapper(path)| path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_escape() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let s = OsStr::from_bytes(b"dir\\\xff/caf\xc3\xa9\x80");
+        let escaped = escape(s);
+        assert_eq!(escaped, "dir\\\\\\xff/caf\u{e9}\\x80");
+        assert_eq!(unescape(&escaped).unwrap(), s);
+        assert_eq!(unescape("a\\tb\\n").unwrap(), "a\tb\n");
+        assert_eq!(unescape("\\q"), None);
+        assert_eq!(unescape("\\x4"), None);
+    }
+}
@@ -0,0 +1,117 @@
+use globset::{Glob, GlobBuilder, GlobSet};
+use ignore::WalkBuilder;
+use std::io;
+use std::path::Path;
+
+/// Parses a glob pattern matched against a whole path
+pub fn parse_glob(pattern: &str) -> Result<Glob, globset::Error> {
+    GlobBuilder::new(pattern).literal_separator(true).build()
+}
+
+/// Combines glob patterns into a set
+pub fn glob_set(globs: Vec<Glob>) -> io::Result<GlobSet> {
+    let mut builder = GlobSet::builder();
+    for glob in globs {
+        builder.add(glob);
+    }
+    builder
+        .build()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Selects files to be scanned by their paths relative to the scanned directory
+pub struct Filter {
+    include: GlobSet,
+    exclude: GlobSet,
+    /// Whether files ignored by `.gitignore`, `.ignore` and `.dedupignore` files are skipped
+    ignore_files: bool,
+}
+
+impl Filter {
+    /// Creates a filter
+    ///
+    /// # Arguments
+    /// * `include` - Patterns of files to be scanned, or nothing to scan all files
+    /// * `exclude` - Patterns of files and directories to be skipped
+    /// * `ignore_files` - Whether to skip files ignored by `.gitignore`, `.ignore`
+    ///   and `.dedupignore` files
+    pub fn new(include: Vec<Glob>, exclude: Vec<Glob>, ignore_files: bool) -> io::Result<Self> {
+        Ok(Self {
+            include: glob_set(include)?,
+            exclude: glob_set(exclude)?,
+            ignore_files,
+        })
+    }
+
+    /// Returns a builder of a walker over a directory, which skips excluded
+    /// directories without entering them
+    ///
+    /// Symbolic links to directories are followed. Ignore files are read
+    /// hierarchically, including those in the parent directories of `root`,
+    /// with `.dedupignore` taking precedence over `.ignore` and `.gitignore`.
+    pub fn walker(&self, root: &Path) -> WalkBuilder {
+        let mut builder = WalkBuilder::new(root);
+        builder
+            .standard_filters(false)
+            .follow_links(true)
+            .parents(self.ignore_files)
+            .ignore(self.ignore_files)
+            .git_ignore(self.ignore_files)
+            .git_exclude(self.ignore_files)
+            .require_git(false);
+        if self.ignore_files {
+            builder.add_custom_ignore_filename(".dedupignore");
+        }
+        let root = root.to_owned();
+        let exclude = self.exclude.clone();
+        builder.filter_entry(move |entry| {
+            let relative = entry.path().strip_prefix(&root).unwrap();
+            entry.depth() == 0 || !exclude.is_match(relative)
+        });
+        builder
+    }
+
+    /// Checks whether a file which is not excluded is scanned
+    pub fn is_included(&self, path: &Path) -> bool {
+        self.include.is_empty() || self.include.is_match(path)
+    }
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self {
+            include: GlobSet::empty(),
+            exclude: GlobSet::empty(),
+            ignore_files: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let filter = Filter::new(
+            vec![parse_glob("**/*.jpg").unwrap()],
+            vec![
+                parse_glob("**/.thumbnails").unwrap(),
+                parse_glob("*.tmp.jpg").unwrap(),
+            ],
+            false,
+        )
+        .unwrap();
+        assert!(filter.is_included(Path::new("a.jpg")));
+        assert!(filter.is_included(Path::new("dir/a.jpg")));
+        assert!(!filter.is_included(Path::new("dir/a.png")));
+        assert!(filter.exclude.is_match(Path::new(".thumbnails")));
+        assert!(filter.exclude.is_match(Path::new("dir/.thumbnails")));
+        assert!(filter.exclude.is_match(Path::new("a.tmp.jpg")));
+        assert!(!filter.exclude.is_match(Path::new("dir/a.tmp.jpg")));
+
+        let filter = Filter::default();
+        assert!(filter.is_included(Path::new("dir/a.png")));
+        assert!(!filter.exclude.is_match(Path::new("dir/a.png")));
+    }
+}
@@ -0,0 +1,309 @@
+use crate::filter::glob_set;
+use crate::hash::Digest;
+use crate::reference::{HashedFile, Inodes, Link, Matches};
+use crate::state::FileId;
+use crate::MatchMode;
+use clap::ValueEnum;
+use globset::{Glob, GlobSet};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Policy for choosing which copy of a duplicate file is kept
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum KeepPolicy {
+    /// Keep the copy found first, in order of the given directories
+    First,
+    /// Keep the copy with the oldest modification time
+    Oldest,
+    /// Keep the copy with the newest modification time
+    Newest,
+    /// Keep the copy with the shortest path
+    ShortestPath,
+}
+
+/// Selects a file to be kept from a group of identical files
+///
+/// Files matching an earlier pattern in `prefer` take priority,
+/// the remaining ties are broken by `policy`.
+struct Keeper {
+    policy: KeepPolicy,
+    prefer: GlobSet,
+}
+
+impl Keeper {
+    fn select(&self, group: &[PathBuf]) -> io::Result<usize> {
+        let mut keys = Vec::with_capacity(group.len());
+        for path in group {
+            let priority = self.prefer.matches(path).into_iter().min();
+            let policy_key = match self.policy {
+                KeepPolicy::First => PolicyKey::None,
+                KeepPolicy::Oldest => PolicyKey::Time(path.metadata()?.modified()?),
+                KeepPolicy::Newest => {
+                    PolicyKey::ReverseTime(std::cmp::Reverse(path.metadata()?.modified()?))
+                }
+                KeepPolicy::ShortestPath => PolicyKey::Length(path.as_os_str().len()),
+            };
+            keys.push((priority.unwrap_or(usize::MAX), policy_key));
+        }
+        // `min_by_key` returns the first of equal elements, so ties are broken by order
+        let index = (0..group.len()).min_by_key(|&i| &keys[i]).unwrap();
+        Ok(index)
+    }
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum PolicyKey {
+    None,
+    Time(SystemTime),
+    ReverseTime(std::cmp::Reverse<SystemTime>),
+    Length(usize),
+}
+
+/// Splits files into groups of files with the same content
+///
+/// Only groups of two or more paths are returned. Paths within a group
+/// keep their relative order from `paths`. Hard links to the same file
+/// end up in the same group, and the file is hashed only once.
+/// Files are hashed in parallel, but the result does not depend on it.
+pub fn group_duplicates(
+    paths: Vec<PathBuf>,
+    mode: MatchMode,
+    inodes: &Inodes,
+) -> io::Result<Vec<Vec<PathBuf>>> {
+    let metadata = paths
+        .par_iter()
+        .map(|path| path.metadata())
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut by_size: HashMap<(u64, Option<OsString>), Vec<Link>> = HashMap::new();
+    for (path, meta) in paths.into_iter().zip(metadata) {
+        let file_name = match mode {
+            MatchMode::Name => Some(path.file_name().unwrap().to_owned()),
+            MatchMode::Content => None,
+        };
+        let file = inodes.get(&path, &meta);
+        by_size
+            .entry((meta.len(), file_name))
+            .or_default()
+            .push((path, file));
+    }
+
+    let buckets: Vec<Vec<Link>> = by_size
+        .into_values()
+        .filter(|files| files.len() > 1)
+        .collect();
+    let mut groups = buckets
+        .into_par_iter()
+        .map(split_bucket)
+        .collect::<io::Result<Vec<_>>>()?
+        .concat();
+    groups.sort();
+    Ok(groups)
+}
+
+/// Splits files of the same size into groups of files with the same content
+fn split_bucket(files: Vec<Link>) -> io::Result<Vec<Vec<PathBuf>>> {
+    if files.iter().all(|(_, file)| Arc::ptr_eq(file, &files[0].1)) {
+        // Links to a single file need not be read
+        return Ok(vec![files.into_iter().map(|(path, _)| path).collect()]);
+    }
+    let mut groups = Vec::new();
+    for files in split_by(files, |file| file.partial_hash())? {
+        for files in split_by(files, |file| file.full_hash())? {
+            groups.push(files.into_iter().map(|(path, _)| path).collect());
+        }
+    }
+    Ok(groups)
+}
+
+/// Splits files by a hash computed in parallel, dropping groups of a single file
+fn split_by(
+    files: Vec<Link>,
+    hash: impl Fn(&HashedFile) -> io::Result<Digest> + Sync,
+) -> io::Result<Vec<Vec<Link>>> {
+    let hashes = files
+        .par_iter()
+        .map(|(_, file)| hash(file))
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut by_hash: HashMap<Digest, Vec<Link>> = HashMap::new();
+    for (file, hash) in files.into_iter().zip(hashes) {
+        by_hash.entry(hash).or_default().push(file);
+    }
+    Ok(by_hash
+        .into_values()
+        .filter(|files| files.len() > 1)
+        .collect())
+}
+
+/// Finds duplicate files among `paths`
+///
+/// In each group of identical files one path is selected by the keep
+/// policy. Every other path is paired with it as a duplicate, or as a link
+/// if it is a hard link to the kept file.
+pub fn find_duplicates_within(
+    paths: Vec<PathBuf>,
+    mode: MatchMode,
+    policy: KeepPolicy,
+    prefer: Vec<Glob>,
+    inodes: Inodes,
+) -> io::Result<Matches> {
+    let keeper = Keeper {
+        policy,
+        prefer: glob_set(prefer)?,
+    };
+
+    let mut matches = Matches::default();
+    for mut group in group_duplicates(paths, mode, &inodes)? {
+        let kept = group.remove(keeper.select(&group)?);
+        let kept_id = FileId::of(&kept.metadata()?);
+        for path in group {
+            let meta = path.metadata()?;
+            if kept_id.is_some() && FileId::of(&meta) == kept_id {
+                matches.linked.push((path, kept.clone()));
+            } else {
+                if let Some(hash) = inodes.get(&path, &meta).known_full_hash() {
+                    matches.hashes.insert(path.clone(), hash);
+                }
+                matches.duplicates.push((path, kept.clone()));
+            }
+        }
+    }
+    Ok(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::parse_glob;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_find_duplicates_within() {
+        let tmp = TempDir::new("test_find_duplicates_within").unwrap();
+        let tmp_path = tmp.path();
+
+        let dir1 = tmp_path.join("dir1");
+        let dir2 = tmp_path.join("dir2").join("originals");
+        fs::create_dir(&dir1).unwrap();
+        fs::create_dir_all(&dir2).unwrap();
+
+        fs::write(dir1.join("file1"), "content1").unwrap();
+        fs::write(dir1.join("copy_of_file1"), "content1").unwrap();
+        fs::write(dir2.join("f1"), "content1").unwrap();
+        fs::write(dir1.join("file2"), "content2").unwrap();
+        fs::write(dir2.join("file3"), "content3").unwrap();
+        let paths = vec![
+            dir1.join("copy_of_file1"),
+            dir1.join("file1"),
+            dir1.join("file2"),
+            dir2.join("f1"),
+            dir2.join("file3"),
+        ];
+
+        let groups =
+            group_duplicates(paths.clone(), MatchMode::Content, &Inodes::default()).unwrap();
+        assert_eq!(
+            groups,
+            [vec![
+                dir1.join("copy_of_file1"),
+                dir1.join("file1"),
+                dir2.join("f1")
+            ]]
+        );
+
+        let duplicates = find_duplicates_within(
+            paths.clone(),
+            MatchMode::Content,
+            KeepPolicy::First,
+            vec![],
+            Inodes::default(),
+        )
+        .unwrap()
+        .duplicates;
+        assert_eq!(
+            duplicates,
+            [
+                (dir1.join("file1"), dir1.join("copy_of_file1")),
+                (dir2.join("f1"), dir1.join("copy_of_file1")),
+            ]
+        );
+
+        let duplicates = find_duplicates_within(
+            paths.clone(),
+            MatchMode::Content,
+            KeepPolicy::ShortestPath,
+            vec![],
+            Inodes::default(),
+        )
+        .unwrap()
+        .duplicates;
+        assert_eq!(
+            duplicates,
+            [
+                (dir1.join("copy_of_file1"), dir1.join("file1")),
+                (dir2.join("f1"), dir1.join("file1")),
+            ]
+        );
+
+        let prefer = vec![parse_glob("**/originals/*").unwrap()];
+        let duplicates = find_duplicates_within(
+            paths,
+            MatchMode::Content,
+            KeepPolicy::First,
+            prefer,
+            Inodes::default(),
+        )
+        .unwrap()
+        .duplicates;
+        assert_eq!(
+            duplicates,
+            [
+                (dir1.join("copy_of_file1"), dir2.join("f1")),
+                (dir1.join("file1"), dir2.join("f1")),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hard_links_within() {
+        let tmp = TempDir::new("test_hard_links_within").unwrap();
+        let tmp_path = tmp.path();
+
+        fs::write(tmp_path.join("file"), "content").unwrap();
+        fs::hard_link(tmp_path.join("file"), tmp_path.join("link")).unwrap();
+        fs::write(tmp_path.join("copy"), "content").unwrap();
+        fs::hard_link(tmp_path.join("copy"), tmp_path.join("link_to_copy")).unwrap();
+        let paths = vec![
+            tmp_path.join("file"),
+            tmp_path.join("link"),
+            tmp_path.join("copy"),
+            tmp_path.join("link_to_copy"),
+        ];
+
+        let matches = find_duplicates_within(
+            paths,
+            MatchMode::Content,
+            KeepPolicy::First,
+            vec![],
+            Inodes::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            matches.duplicates,
+            [
+                (tmp_path.join("copy"), tmp_path.join("file")),
+                (tmp_path.join("link_to_copy"), tmp_path.join("file")),
+            ]
+        );
+        assert_eq!(
+            matches.linked,
+            [(tmp_path.join("link"), tmp_path.join("file"))]
+        );
+    }
+}
@@ -0,0 +1,286 @@
+use clap::builder::{PossibleValuesParser, TypedValueParser, ValueParser};
+use clap::ValueEnum;
+use md5::Md5;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+use xxhash_rust::xxh3::Xxh3;
+
+/// Number of leading bytes hashed to quickly tell apart files of the same size
+pub const PARTIAL_HASH_SIZE: u64 = 4096;
+
+/// Maximum length of a digest in bytes
+const MAX_DIGEST_LEN: usize = 32;
+
+/// A content hash of a file or of its part
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Digest {
+    bytes: [u8; MAX_DIGEST_LEN],
+    len: usize,
+}
+
+impl Digest {
+    fn new(bytes: &[u8]) -> Self {
+        let mut digest = Self {
+            bytes: [0; MAX_DIGEST_LEN],
+            len: bytes.len(),
+        };
+        digest.bytes[..bytes.len()].copy_from_slice(bytes);
+        digest
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.as_bytes() {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Digest({self})")
+    }
+}
+
+/// Parses a hash written as a hexadecimal string
+pub fn parse_digest(hex: &str) -> io::Result<Digest> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("invalid hash {hex:?}"));
+    if !hex.len().is_multiple_of(2) || hex.len() > 2 * MAX_DIGEST_LEN {
+        return Err(invalid());
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(invalid)?;
+    Ok(Digest::new(&bytes))
+}
+
+/// Hash algorithm comparing files, also used to read checksum manifests
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    ValueEnum,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    /// BLAKE3, as written by `b3sum`
+    // Indexes, plans, journals and caches written before the algorithm was
+    // recorded in them only hold BLAKE3 hashes, so a missing algorithm
+    // is read as the default
+    #[default]
+    Blake3,
+    /// SHA-256, as written by `sha256sum`
+    Sha256,
+    /// 128-bit XXH3, as written by `xxh128sum`; fast, but not cryptographic
+    Xxh3,
+    /// MD5, as written by `md5sum`
+    Md5,
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.to_possible_value().unwrap().get_name())
+    }
+}
+
+impl Algorithm {
+    /// Algorithms offered for comparing files
+    ///
+    /// MD5 collisions are easy to craft, so MD5 is only used to read checksum manifests.
+    const COMPARING: [Self; 3] = [Self::Blake3, Self::Sha256, Self::Xxh3];
+
+    /// Returns a parser of the algorithms offered for comparing files
+    pub fn comparing_parser() -> ValueParser {
+        let values = Self::COMPARING.map(|algorithm| algorithm.to_possible_value().unwrap());
+        ValueParser::new(
+            PossibleValuesParser::new(values).map(|name| Self::from_str(&name, false).unwrap()),
+        )
+    }
+
+    /// Returns the algorithm named by the tag of a BSD-style checksum line
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        match tag {
+            "BLAKE3" => Some(Self::Blake3),
+            "SHA256" => Some(Self::Sha256),
+            "XXH128" => Some(Self::Xxh3),
+            "MD5" => Some(Self::Md5),
+            _ => None,
+        }
+    }
+
+    /// Returns the length of a digest in bytes
+    pub fn digest_len(self) -> usize {
+        match self {
+            Self::Blake3 | Self::Sha256 => 32,
+            Self::Xxh3 | Self::Md5 => 16,
+        }
+    }
+
+    /// Computes a hash of a file content
+    ///
+    /// # Arguments
+    /// * `path` - A path to a file
+    /// * `limit` - Maximum number of leading bytes to hash, the whole file is hashed if `None`
+    pub fn hash_file(self, path: impl AsRef<Path>, limit: Option<u64>) -> io::Result<Digest> {
+        let file = File::open(path)?;
+        let mut hasher = Hasher::new(self);
+        match limit {
+            Some(limit) => io::copy(&mut file.take(limit), &mut hasher)?,
+            None => io::copy(&mut &file, &mut hasher)?,
+        };
+        Ok(hasher.finalize())
+    }
+}
+
+/// A hasher of any of the supported algorithms
+enum Hasher {
+    Blake3(Box<blake3::Hasher>),
+    Sha256(Sha256),
+    Xxh3(Box<Xxh3>),
+    Md5(Md5),
+}
+
+impl Hasher {
+    fn new(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Blake3 => Self::Blake3(Box::default()),
+            Algorithm::Sha256 => Self::Sha256(Sha256::new()),
+            Algorithm::Xxh3 => Self::Xxh3(Box::default()),
+            Algorithm::Md5 => Self::Md5(Md5::new()),
+        }
+    }
+
+    fn finalize(self) -> Digest {
+        match self {
+            Self::Blake3(hasher) => Digest::new(hasher.finalize().as_bytes()),
+            Self::Sha256(hasher) => Digest::new(&hasher.finalize()),
+            Self::Xxh3(hasher) => Digest::new(&hasher.digest128().to_be_bytes()),
+            Self::Md5(hasher) => Digest::new(&hasher.finalize()),
+        }
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Blake3(hasher) => {
+                hasher.update(buf);
+            }
+            Self::Sha256(hasher) => hasher.update(buf),
+            Self::Xxh3(hasher) => hasher.update(buf),
+            Self::Md5(hasher) => hasher.update(buf),
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Compares the content of two files byte by byte
+pub fn compare_files(a: impl AsRef<Path>, b: impl AsRef<Path>) -> io::Result<bool> {
+    let (a, b) = (File::open(a)?, File::open(b)?);
+    if a.metadata()?.len() != b.metadata()?.len() {
+        return Ok(false);
+    }
+    let mut a = BufReader::new(a);
+    let mut b = BufReader::new(b);
+    let mut buf_a = [0; 8192];
+    let mut buf_b = [0; 8192];
+    loop {
+        let len = a.read(&mut buf_a)?;
+        if len == 0 {
+            // Both files have the same size, so `b` has ended too
+            return Ok(true);
+        }
+        b.read_exact(&mut buf_b[..len])?;
+        if buf_a[..len] != buf_b[..len] {
+            return Ok(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_algorithms() {
+        let tmp = TempDir::new("test_algorithms").unwrap();
+        let path = tmp.path().join("file");
+        std::fs::write(&path, "hello\n").unwrap();
+        let blake3 = blake3::hash(b"hello\n").to_string();
+        let expected = [
+            (Algorithm::Blake3, blake3.as_str()),
+            (
+                Algorithm::Sha256,
+                "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03",
+            ),
+            (Algorithm::Md5, "b1946ac92492d2347c6235b4d2611184"),
+        ];
+        for (algorithm, hex) in expected {
+            let digest = algorithm.hash_file(&path, None).unwrap();
+            assert_eq!(digest.to_string(), hex);
+            assert_eq!(digest.as_bytes().len(), algorithm.digest_len());
+            assert_eq!(parse_digest(hex).unwrap(), digest);
+        }
+        let digest = Algorithm::Xxh3.hash_file(&path, None).unwrap();
+        assert_eq!(digest.as_bytes().len(), 16);
+        assert_ne!(digest, Algorithm::Xxh3.hash_file(&path, Some(3)).unwrap());
+        assert!(parse_digest("abc").is_err());
+    }
+
+    #[test]
+    fn test_comparing_parser() {
+        let command = clap::Command::new("dedup").arg(
+            clap::Arg::new("hash")
+                .long("hash")
+                .value_parser(Algorithm::comparing_parser()),
+        );
+        let matches = command
+            .clone()
+            .try_get_matches_from(["dedup", "--hash", "xxh3"]);
+        assert_eq!(matches.unwrap().get_one("hash"), Some(&Algorithm::Xxh3));
+        assert!(command
+            .try_get_matches_from(["dedup", "--hash", "md5"])
+            .is_err());
+    }
+
+    #[test]
+    fn test_compare_files() {
+        let tmp = TempDir::new("test_compare_files").unwrap();
+        let path = |name| tmp.path().join(name);
+        let data = vec![0x55; 20000];
+        std::fs::write(path("a"), &data).unwrap();
+        std::fs::write(path("b"), &data).unwrap();
+        let mut other = data.clone();
+        other[19999] = 0;
+        std::fs::write(path("c"), &other).unwrap();
+        std::fs::write(path("d"), &data[..100]).unwrap();
+        assert!(compare_files(path("a"), path("b")).unwrap());
+        assert!(!compare_files(path("a"), path("c")).unwrap());
+        assert!(!compare_files(path("a"), path("d")).unwrap());
+    }
+}
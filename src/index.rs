@@ -0,0 +1,184 @@
+use crate::escape;
+use crate::hash::{parse_digest, Algorithm, Digest, PARTIAL_HASH_SIZE};
+use crate::reference::Inodes;
+use crate::state::FileState;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Version of the index file format
+const INDEX_VERSION: u32 = 1;
+
+/// The first line of an index file
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: u32,
+    /// A path to the indexed directory
+    #[serde(with = "escape::path")]
+    root: PathBuf,
+    /// Hash algorithm of all hashes in the file
+    #[serde(default)]
+    algorithm: Algorithm,
+}
+
+/// An indexed reference file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IndexEntry {
+    /// State of the file with a hash of its whole content
+    #[serde(flatten)]
+    pub state: FileState,
+    /// A hash of the first `PARTIAL_HASH_SIZE` bytes, if the file is larger
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub partial: Option<String>,
+}
+
+impl IndexEntry {
+    /// Returns the partial and the full hash of the file
+    pub fn hashes(&self) -> io::Result<(Digest, Digest)> {
+        let Some(full) = &self.state.hash else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("index entry for {:?} has no hash", self.state.path),
+            ));
+        };
+        let full = parse_digest(full)?;
+        let partial = match &self.partial {
+            Some(partial) => parse_digest(partial)?,
+            None => full,
+        };
+        Ok((partial, full))
+    }
+}
+
+/// Reference files with their hashes, saved to be used instead of a directory
+#[derive(Debug)]
+pub struct Index {
+    pub root: PathBuf,
+    /// The algorithm of all hashes in the index
+    pub algorithm: Algorithm,
+    pub entries: Vec<IndexEntry>,
+}
+
+impl Index {
+    /// Hashes reference files
+    ///
+    /// # Arguments
+    /// * `root` - A path to the indexed directory
+    /// * `paths` - Paths to the files in the directory
+    /// * `inodes` - Hashed files shared by all links to them, and their hash algorithm
+    pub fn build(root: &Path, paths: Vec<PathBuf>, inodes: &Inodes) -> io::Result<Self> {
+        let entries = paths
+            .into_par_iter()
+            .map(|path| {
+                let state = FileState::capture(&path)?;
+                let file = inodes.get(&path, &path.metadata()?);
+                let partial = if state.size > PARTIAL_HASH_SIZE {
+                    Some(file.partial_hash()?.to_string())
+                } else {
+                    None
+                };
+                Ok(IndexEntry {
+                    state: FileState {
+                        hash: Some(file.full_hash()?.to_string()),
+                        ..state
+                    },
+                    partial,
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Self {
+            root: std::path::absolute(root)?,
+            algorithm: inodes.algorithm(),
+            entries,
+        })
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+        let header: Header = match lines.next() {
+            Some(line) => serde_json::from_str(&line?)?,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "empty index")),
+        };
+        if header.version != INDEX_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported index version {}", header.version),
+            ));
+        }
+        let entries = lines
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect::<io::Result<_>>()?;
+        Ok(Self {
+            root: header.root,
+            algorithm: header.algorithm,
+            entries,
+        })
+    }
+
+    /// Writes the index, one JSON object per line
+    pub fn write(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let header = Header {
+            version: INDEX_VERSION,
+            root: self.root.clone(),
+            algorithm: self.algorithm,
+        };
+        serde_json::to_writer(&mut writer, &header)?;
+        writeln!(writer)?;
+        for entry in &self.entries {
+            serde_json::to_writer(&mut writer, entry)?;
+            writeln!(writer)?;
+        }
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_index() {
+        let tmp = TempDir::new("test_index").unwrap();
+        let root = tmp.path().join("ref");
+        fs::create_dir(&root).unwrap();
+        let large = vec![0x55; 2 * PARTIAL_HASH_SIZE as usize];
+        fs::write(root.join("small"), "content").unwrap();
+        fs::write(root.join("large"), &large).unwrap();
+
+        let paths = vec![root.join("large"), root.join("small")];
+        let inodes = Inodes::new(Algorithm::Sha256, None);
+        let index = Index::build(&root, paths, &inodes).unwrap();
+        index.write(tmp.path().join("ref.idx")).unwrap();
+        let read = Index::read(tmp.path().join("ref.idx")).unwrap();
+        assert_eq!(read.root, root);
+        assert_eq!(read.algorithm, Algorithm::Sha256);
+        assert_eq!(read.entries, index.entries);
+
+        let (partial, full) = read.entries[0].hashes().unwrap();
+        assert_eq!(
+            partial,
+            Algorithm::Sha256
+                .hash_file(root.join("large"), Some(PARTIAL_HASH_SIZE))
+                .unwrap()
+        );
+        assert_eq!(
+            full,
+            Algorithm::Sha256
+                .hash_file(root.join("large"), None)
+                .unwrap()
+        );
+        let (partial, full) = read.entries[1].hashes().unwrap();
+        assert_eq!(partial, full);
+        assert_eq!(
+            full,
+            Algorithm::Sha256
+                .hash_file(root.join("small"), None)
+                .unwrap()
+        );
+    }
+}
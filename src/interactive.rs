@@ -0,0 +1,152 @@
+use crate::action::Action;
+use crate::report::Record;
+use crate::table::format_time;
+use std::io::{self, BufRead, Write};
+
+/// What the user decided to do with a duplicate file
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Choice {
+    /// Apply an action to the duplicate
+    Apply(Action),
+    /// Keep the duplicate as it is
+    Keep,
+    /// Leave the duplicate undecided
+    Skip,
+    /// Stop processing duplicates
+    Quit,
+}
+
+/// Asks the user what to do with each duplicate file
+///
+/// Questions are written to `output` and answers are read line by line from
+/// `input`. An upper-case answer applies the choice to all remaining duplicates.
+pub struct Prompt<R: BufRead, W: Write> {
+    input: R,
+    output: W,
+    /// Action applied by the apply answer
+    action: Action,
+    /// Action applied by the link answer
+    link: Action,
+    /// Choice applied to all remaining duplicates
+    remaining: Option<Choice>,
+}
+
+impl Prompt<io::StdinLock<'static>, io::Stderr> {
+    /// Creates a prompt on the terminal
+    ///
+    /// Questions are written to the standard error, so they do not mix with
+    /// a machine-readable report on the standard output.
+    pub fn terminal(action: Action, link: Action) -> Self {
+        Self::new(io::stdin().lock(), io::stderr(), action, link)
+    }
+}
+
+impl<R: BufRead, W: Write> Prompt<R, W> {
+    /// Creates a prompt
+    ///
+    /// # Arguments
+    /// * `input` - A reader providing answers
+    /// * `output` - A writer receiving questions
+    /// * `action` - The selected action, applied when the user chooses to apply it
+    /// * `link` - The action applied when the user chooses to link a duplicate
+    pub fn new(input: R, output: W, action: Action, link: Action) -> Self {
+        Self {
+            input,
+            output,
+            action,
+            link,
+            remaining: None,
+        }
+    }
+
+    /// Asks what to do with a duplicate file
+    ///
+    /// Returns `Choice::Quit` when the input ends.
+    pub fn ask(&mut self, record: &Record) -> io::Result<Choice> {
+        if let Some(choice) = self.remaining {
+            return Ok(choice);
+        }
+        writeln!(self.output, "Duplicate: {:?}", record.target)?;
+        writeln!(
+            self.output,
+            "  {} bytes, modified {}",
+            record.size,
+            record.target_modified.map_or("?".to_owned(), format_time)
+        )?;
+        writeln!(self.output, "Reference: {:?}", record.reference)?;
+        writeln!(
+            self.output,
+            "  modified {}",
+            record
+                .reference_modified
+                .map_or("?".to_owned(), format_time)
+        )?;
+        loop {
+            write!(
+                self.output,
+                "[k]eep, [a]pply ({}), [d]elete, [l]ink ({}), [s]kip, [q]uit (upper case for all remaining)? ",
+                self.action, self.link
+            )?;
+            self.output.flush()?;
+            let mut answer = String::new();
+            if self.input.read_line(&mut answer)? == 0 {
+                writeln!(self.output)?;
+                return Ok(Choice::Quit);
+            }
+            let answer = answer.trim();
+            let choice = match answer.to_lowercase().as_str() {
+                "k" | "keep" => Choice::Keep,
+                "a" | "apply" => Choice::Apply(self.action),
+                "d" | "delete" => Choice::Apply(Action::Delete),
+                "l" | "link" => Choice::Apply(self.link),
+                "s" | "skip" => Choice::Skip,
+                "q" | "quit" => Choice::Quit,
+                _ => {
+                    writeln!(self.output, "Unknown answer {answer:?}")?;
+                    continue;
+                }
+            };
+            if answer.starts_with(|c: char| c.is_ascii_uppercase()) {
+                self.remaining = Some(choice);
+            }
+            return Ok(choice);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::Outcome;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_prompt() {
+        let record = Record {
+            target: PathBuf::from("target/file"),
+            reference: PathBuf::from("ref/file"),
+            size: 7,
+            target_modified: None,
+            reference_modified: None,
+            algorithm: None,
+            hash: None,
+            action: Action::Delete,
+            outcome: Outcome::DryRun,
+            message: None,
+        };
+        let input = "x\nd\na\nk\nL\n";
+        let mut prompt = Prompt::new(input.as_bytes(), Vec::new(), Action::Trash, Action::Symlink);
+        assert_eq!(prompt.ask(&record).unwrap(), Choice::Apply(Action::Delete));
+        assert_eq!(prompt.ask(&record).unwrap(), Choice::Apply(Action::Trash));
+        assert_eq!(prompt.ask(&record).unwrap(), Choice::Keep);
+        assert_eq!(prompt.ask(&record).unwrap(), Choice::Apply(Action::Symlink));
+        assert_eq!(prompt.ask(&record).unwrap(), Choice::Apply(Action::Symlink));
+        let output = String::from_utf8(prompt.output).unwrap();
+        assert!(output.starts_with("Duplicate: \"target/file\"\n  7 bytes, modified ?\n"));
+        assert!(output.contains("Unknown answer \"x\""));
+        assert!(output.contains("[a]pply (trash), [d]elete, [l]ink (symlink)"));
+
+        let mut prompt = Prompt::new("".as_bytes(), io::sink(), Action::Delete, Action::Hardlink);
+        assert_eq!(prompt.ask(&record).unwrap(), Choice::Quit);
+    }
+}
@@ -0,0 +1,328 @@
+use crate::action::{move_file, replace_with, Action};
+use crate::escape;
+use crate::hash::Algorithm;
+use crate::state::FileId;
+use crate::trash::trash_info_path;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// A record of an action applied to a duplicate file
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct JournalEntry {
+    pub action: Action,
+    #[serde(with = "escape::path")]
+    pub target: PathBuf,
+    #[serde(with = "escape::path")]
+    pub reference: PathBuf,
+    /// A new location of the duplicate, for actions which move it
+    #[serde(with = "escape::option_path", default)]
+    pub destination: Option<PathBuf>,
+    pub size: u64,
+    /// Identity of the duplicate before the action was applied
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<FileId>,
+    /// Hash algorithm of `hash`
+    #[serde(default)]
+    pub algorithm: Algorithm,
+    pub hash: String,
+    pub modified: SystemTime,
+    /// Unix permission bits
+    pub mode: Option<u32>,
+}
+
+impl JournalEntry {
+    /// Captures the state of a duplicate file before an action is applied to it
+    ///
+    /// Paths are recorded as absolute, so the journal does not depend on the current directory.
+    pub fn new(
+        action: Action,
+        target: &Path,
+        reference: &Path,
+        algorithm: Algorithm,
+        hash: String,
+    ) -> io::Result<Self> {
+        let meta = target.metadata()?;
+        Ok(Self {
+            action,
+            target: std::path::absolute(target)?,
+            reference: std::path::absolute(reference)?,
+            destination: None,
+            size: meta.len(),
+            id: FileId::of(&meta),
+            algorithm,
+            hash,
+            modified: meta.modified()?,
+            mode: file_mode(&meta),
+        })
+    }
+}
+
+#[cfg(unix)]
+fn file_mode(meta: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(meta.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn file_mode(_meta: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// An append-only log of applied actions
+pub struct Journal {
+    file: File,
+}
+
+impl Journal {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::options().create(true).append(true).open(path)?;
+        Ok(Self { file })
+    }
+
+    /// Appends an entry and waits until it is written to the disk
+    pub fn append(&mut self, entry: &JournalEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.sync_data()
+    }
+}
+
+/// Reads all entries of a journal in the order they were written
+pub fn read_journal(path: impl AsRef<Path>) -> io::Result<Vec<JournalEntry>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut entries = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {}", index + 1, e),
+            )
+        })?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Reverts an action recorded in a journal
+///
+/// Deleted and linked duplicates are restored by copying the reference file,
+/// which must still have the recorded content. Moved duplicates are moved back.
+/// Nothing is restored over a file put at the path of the duplicate since then.
+///
+/// Entries are written before their actions are applied, so an entry may
+/// record an action which failed or was skipped and left the duplicate as it was.
+///
+/// # Returns
+/// * `false` if the duplicate was left untouched, so there was nothing to revert
+pub fn undo(entry: &JournalEntry) -> io::Result<bool> {
+    if entry.action != Action::Reflink && is_untouched(entry)? {
+        return Ok(false);
+    }
+    revert(entry).map(|()| true)
+}
+
+/// Checks that the duplicate is still the same file with the recorded content
+fn is_untouched(entry: &JournalEntry) -> io::Result<bool> {
+    let meta = match (entry.id, entry.target.symlink_metadata()) {
+        (Some(_), Ok(meta)) => meta,
+        _ => return Ok(false),
+    };
+    Ok(meta.is_file()
+        && FileId::of(&meta) == entry.id
+        && entry.algorithm.hash_file(&entry.target, None)?.to_string() == entry.hash)
+}
+
+fn revert(entry: &JournalEntry) -> io::Result<()> {
+    match entry.action {
+        Action::Delete | Action::Hardlink | Action::Symlink => {
+            check_target(entry)?;
+            if entry
+                .algorithm
+                .hash_file(&entry.reference, None)?
+                .to_string()
+                != entry.hash
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "reference file has changed",
+                ));
+            }
+            fs::create_dir_all(entry.target.parent().unwrap())?;
+            replace_with(&entry.target, |tmp| {
+                fs::copy(&entry.reference, tmp)?;
+                restore_metadata(tmp, entry)
+            })
+        }
+        Action::Reflink => Ok(()),
+        Action::Move | Action::Trash => {
+            let destination = entry.destination.as_ref().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "destination is not recorded")
+            })?;
+            if entry.target.symlink_metadata().is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "target file already exists",
+                ));
+            }
+            fs::create_dir_all(entry.target.parent().unwrap())?;
+            move_file(destination, &entry.target)?;
+            if entry.action == Action::Trash {
+                fs::remove_file(trash_info_path(destination))?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Checks that the target of a deleted or linked duplicate is still what the action left there
+///
+/// A file put at the same path since then must not be overwritten.
+fn check_target(entry: &JournalEntry) -> io::Result<()> {
+    let target = &entry.target;
+    let error = |kind, message: &str| Err(io::Error::new(kind, message));
+    match entry.action {
+        Action::Delete if target.symlink_metadata().is_ok() => {
+            error(io::ErrorKind::AlreadyExists, "target file already exists")
+        }
+        Action::Hardlink => {
+            let id = target
+                .symlink_metadata()
+                .ok()
+                .and_then(|meta| FileId::of(&meta));
+            if id.is_none() || id != FileId::of(&entry.reference.metadata()?) {
+                return error(
+                    io::ErrorKind::InvalidData,
+                    "target file is not a hard link to the reference file",
+                );
+            }
+            Ok(())
+        }
+        Action::Symlink => {
+            if !target.is_symlink()
+                || target.canonicalize().ok() != Some(entry.reference.canonicalize()?)
+            {
+                return error(
+                    io::ErrorKind::InvalidData,
+                    "target file is not a symbolic link to the reference file",
+                );
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn restore_metadata(path: &Path, entry: &JournalEntry) -> io::Result<()> {
+    File::open(path)?.set_modified(entry.modified)?;
+    #[cfg(unix)]
+    if let Some(mode) = entry.mode {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::ActionOptions;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_undo() {
+        let tmp = TempDir::new("test_undo").unwrap();
+        let reference = tmp.path().join("reference");
+        let root = tmp.path().join("target");
+        let target = root.join("file");
+        let journal_path = tmp.path().join("journal");
+        fs::create_dir(&root).unwrap();
+        fs::write(&reference, "content").unwrap();
+        fs::write(&target, "content").unwrap();
+        let modified = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        File::open(&target).unwrap().set_modified(modified).unwrap();
+
+        let mut journal = Journal::open(&journal_path).unwrap();
+        for action in [Action::Symlink, Action::Delete] {
+            let algorithm = Algorithm::default();
+            let hash = algorithm.hash_file(&target, None).unwrap().to_string();
+            let entry = JournalEntry::new(action, &target, &reference, algorithm, hash).unwrap();
+            let options = ActionOptions {
+                action,
+                relative: false,
+                quarantine: None,
+            };
+            options
+                .apply(&target, &reference, &root, |_| journal.append(&entry))
+                .unwrap();
+            if action == Action::Symlink {
+                assert!(target.is_symlink());
+                assert!(undo(&entry).unwrap());
+            }
+        }
+        assert!(!target.exists());
+
+        let entries = read_journal(&journal_path).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(undo(&entries[1]).unwrap());
+        assert!(!target.is_symlink());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "content");
+        assert_eq!(target.metadata().unwrap().modified().unwrap(), modified);
+    }
+
+    #[test]
+    fn test_undo_replaced_link() {
+        let tmp = TempDir::new("test_undo_replaced_link").unwrap();
+        let reference = tmp.path().join("reference");
+        let other = tmp.path().join("other");
+        let target = tmp.path().join("target");
+        fs::write(&reference, "content").unwrap();
+        fs::write(&other, "content").unwrap();
+        fs::write(&target, "content").unwrap();
+
+        for action in [Action::Hardlink, Action::Symlink] {
+            let algorithm = Algorithm::default();
+            let hash = algorithm.hash_file(&target, None).unwrap().to_string();
+            let entry = JournalEntry::new(action, &target, &reference, algorithm, hash).unwrap();
+            let options = ActionOptions {
+                action,
+                relative: false,
+                quarantine: None,
+            };
+            options
+                .apply(&target, &other, tmp.path(), |_| Ok(()))
+                .unwrap();
+            assert!(undo(&entry).is_err());
+            options
+                .apply(&target, &reference, tmp.path(), |_| Ok(()))
+                .unwrap();
+            assert!(undo(&entry).unwrap());
+            assert!(!target.is_symlink());
+        }
+    }
+
+    #[test]
+    fn test_undo_not_applied() {
+        let tmp = TempDir::new("test_undo_not_applied").unwrap();
+        let reference = tmp.path().join("reference");
+        let target = tmp.path().join("target");
+        fs::write(&reference, "content").unwrap();
+        fs::write(&target, "content").unwrap();
+
+        // As written for a hard link skipped because the files are on different filesystems
+        for action in [Action::Hardlink, Action::Delete, Action::Move] {
+            let algorithm = Algorithm::default();
+            let hash = algorithm.hash_file(&target, None).unwrap().to_string();
+            let entry = JournalEntry::new(action, &target, &reference, algorithm, hash).unwrap();
+            assert!(!undo(&entry).unwrap());
+            assert_eq!(fs::read_to_string(&target).unwrap(), "content");
+        }
+    }
+}
@@ -1,153 +1,978 @@
-use clap::Parser;
+mod action;
+mod cache;
+mod escape;
+mod filter;
+mod group;
+mod hash;
+mod index;
+mod interactive;
+mod journal;
+mod manifest;
+mod plan;
+mod reference;
+mod reflink;
+mod report;
+mod roots;
+mod script;
+mod state;
+mod table;
+mod trash;
+
+use action::Action;
+use action::ActionOptions;
+use cache::HashCache;
+use clap::{Parser, Subcommand, ValueEnum};
+use filter::{parse_glob, Filter};
+use globset::Glob;
+use group::{find_duplicates_within, KeepPolicy};
+use hash::{compare_files, parse_digest, Algorithm};
+use ignore::WalkState;
+use index::Index;
+use interactive::{Choice, Prompt};
+use journal::{read_journal, undo, Journal, JournalEntry};
+use manifest::{Checksum, Manifest};
+use plan::{Plan, PlanEntry};
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use reference::{Inodes, Match, Matches, ReferenceData};
+use report::{Format, Outcome, Record, Reporter};
+use roots::check_roots;
+use script::{check_scriptable, ScriptWriter};
+use state::FileState;
 use std::collections::HashMap;
-use std::ffi::OsString;
-use std::fs::File;
-use std::io::Read;
+use std::io;
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
 use std::process::ExitCode;
-use std::{fs, io};
+use std::sync::{Arc, Mutex};
+use table::{Separator, TableWriter};
 
 /// File deduplication tool
 #[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    options: Options,
+    #[command(flatten)]
+    dirs: Dirs,
+}
+
+/// Reference and target directories
+#[derive(clap::Args, Debug)]
+struct Dirs {
+    /// Path to a reference directory
+    #[arg(required_unless_present_any = ["references", "indexes", "manifests"])]
+    reference: Option<PathBuf>,
+    /// Path to a target directory to be deduplicated
+    #[arg(required_unless_present = "targets")]
+    target: Option<PathBuf>,
+    /// Path to an additional reference directory (may be repeated)
+    #[arg(short('r'), long("reference"), value_name = "DIR")]
+    references: Vec<PathBuf>,
+    /// Path to an additional target directory (may be repeated)
+    #[arg(short('t'), long("target"), value_name = "DIR")]
+    targets: Vec<PathBuf>,
+    /// Path to a reference index written with `dedup index` (may be repeated)
+    #[arg(long("index"), value_name = "FILE")]
+    indexes: Vec<PathBuf>,
+    /// Path to a checksum manifest listing reference files (may be repeated)
+    #[arg(long("manifest"), value_name = "FILE")]
+    manifests: Vec<PathBuf>,
+    /// Hash algorithm of manifest lines which do not name one
+    #[arg(long("manifest-hash"), value_name = "ALGORITHM", value_enum)]
+    manifest_hash: Option<Algorithm>,
+}
+
+impl Dirs {
+    /// Returns all reference directories, the positional one first
+    fn references(&self) -> Vec<PathBuf> {
+        self.reference
+            .iter()
+            .chain(&self.references)
+            .cloned()
+            .collect()
+    }
+
+    /// Returns all target directories, the positional one first
+    fn targets(&self) -> Vec<PathBuf> {
+        self.target.iter().chain(&self.targets).cloned().collect()
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Remove duplicate files found within the given directories
+    #[command(name = "self")]
+    SelfDedup {
+        #[command(flatten)]
+        options: Options,
+        /// Which copy of a duplicate file is kept
+        #[arg(
+            short('k'),
+            long("keep"),
+            value_name = "POLICY",
+            value_enum,
+            default_value_t = KeepPolicy::First
+        )]
+        keep: KeepPolicy,
+        /// Prefer keeping files whose paths match a glob pattern, earlier patterns take priority
+        #[arg(short('p'), long("prefer"), value_name = "GLOB", value_parser = parse_glob)]
+        prefer: Vec<Glob>,
+        /// Paths to directories to be deduplicated
+        #[arg(required = true)]
+        roots: Vec<PathBuf>,
+    },
+    /// Write a plan of actions to be reviewed and applied later with `dedup apply`
+    Plan {
+        #[command(flatten)]
+        scan: ScanOptions,
+        #[command(flatten)]
+        action: ActionOptions,
+        /// Path to the plan file to be written
+        #[arg(short('o'), long("output"), value_name = "FILE")]
+        output: PathBuf,
+        #[command(flatten)]
+        dirs: Dirs,
+    },
+    /// Scan a reference directory once and save hashes of its files to an index
+    Index {
+        #[command(flatten)]
+        walk: WalkOptions,
+        /// Path to the index file to be written
+        #[arg(short('o'), long("output"), value_name = "FILE")]
+        output: PathBuf,
+        /// Path to a reference directory
+        reference: PathBuf,
+    },
+    /// Apply a plan written with `dedup plan`, skipping files changed since then
+    Apply {
+        #[command(flatten)]
+        run: RunOptions,
+        /// Path to a plan file
+        plan: PathBuf,
+    },
+    /// Revert actions recorded in a journal
+    Undo {
+        /// Perform a trial run with no changes made
+        #[arg(short('n'), long("dry-run"))]
+        dry_run: bool,
+        /// Path to a journal written with `--journal`
+        journal: PathBuf,
+    },
+}
+
+/// Options shared by all deduplication modes
+#[derive(clap::Args, Debug)]
+struct Options {
+    #[command(flatten)]
+    run: RunOptions,
+    #[command(flatten)]
+    scan: ScanOptions,
+    #[command(flatten)]
+    action: ActionOptions,
+    /// Compare the content of both files again right before applying an action
+    #[arg(long("verify-content"))]
+    verify_content: bool,
+    /// Compare both files byte by byte right before applying an action, instead of by hash
+    #[arg(long("verify-bytes"))]
+    verify_bytes: bool,
+}
+
+impl Options {
+    fn recheck(&self) -> Recheck {
+        if self.verify_bytes {
+            Recheck::Bytes
+        } else if self.verify_content {
+            Recheck::Hash(self.scan.walk.hash)
+        } else {
+            Recheck::None
+        }
+    }
+}
+
+/// How the content of a duplicate is compared again right before an action is applied
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Recheck {
+    None,
+    /// Both files are hashed again with the algorithm
+    Hash(Algorithm),
+    /// Both files are read and compared byte by byte
+    Bytes,
+}
+
+/// Options controlling how files are scanned and compared
+#[derive(clap::Args, Debug)]
+struct ScanOptions {
+    /// How reference candidates are selected for a target file [default: name, content for `dedup self`]
+    #[arg(short('m'), long("match"), value_name = "MODE", value_enum)]
+    match_mode: Option<MatchMode>,
+    #[command(flatten)]
+    walk: WalkOptions,
+}
+
+impl ScanOptions {
+    /// Returns the selected match mode, or `default` if none was given
+    fn match_mode(&self, default: MatchMode) -> MatchMode {
+        self.match_mode.unwrap_or(default)
+    }
+}
+
+/// Options controlling how directories are walked and files are hashed
+#[derive(clap::Args, Debug)]
+struct WalkOptions {
+    /// Scan only files matching a glob pattern (may be repeated)
+    #[arg(long("include"), value_name = "GLOB", value_parser = parse_glob)]
+    include: Vec<Glob>,
+    /// Skip files and directories matching a glob pattern (may be repeated)
+    #[arg(long("exclude"), value_name = "GLOB", value_parser = parse_glob)]
+    exclude: Vec<Glob>,
+    /// Skip files ignored by .gitignore, .ignore and .dedupignore files
+    #[arg(long("ignore-files"))]
+    ignore_files: bool,
+    /// Number of threads scanning and comparing files [default: number of CPUs]
+    #[arg(long("jobs"), value_name = "N")]
+    jobs: Option<NonZeroUsize>,
+    /// Keep file hashes in a cache file reused across runs
+    #[arg(long("cache"), value_name = "FILE")]
+    cache: Option<PathBuf>,
+    /// Hash algorithm comparing files
+    #[arg(
+        long("hash"),
+        value_name = "ALGORITHM",
+        value_parser = Algorithm::comparing_parser(),
+        default_value_t = Algorithm::Blake3
+    )]
+    hash: Algorithm,
+}
+
+impl WalkOptions {
+    fn filter(&self) -> io::Result<Filter> {
+        Filter::new(
+            self.include.clone(),
+            self.exclude.clone(),
+            self.ignore_files,
+        )
+    }
+
+    fn cache(&self) -> io::Result<Option<Arc<HashCache>>> {
+        let cache = self.cache.as_ref().map(HashCache::open).transpose()?;
+        Ok(cache.map(Arc::new))
+    }
+
+    /// Returns an empty set of files to be hashed with the selected algorithm
+    fn inodes(&self, cache: &Option<Arc<HashCache>>) -> Inodes {
+        Inodes::new(self.hash, cache.clone())
+    }
+
+    fn thread_pool(&self) -> io::Result<ThreadPool> {
+        ThreadPoolBuilder::new()
+            .num_threads(self.jobs.map_or(0, NonZeroUsize::get))
+            .build()
+            .map_err(io::Error::other)
+    }
+}
+
+/// Options controlling how actions are applied and reported
+#[derive(clap::Args, Debug)]
+struct RunOptions {
     /// Perform a trial run with no changes made
     #[arg(short('n'), long("dry-run"))]
     dry_run: bool,
-    /// Path to a reference directory
-    reference: PathBuf,
-    /// Path to a target directory to be deduplicated
-    target: PathBuf,
+    /// Write a shell script performing the actions instead of applying them
+    #[arg(
+        short('s'),
+        long("script"),
+        value_name = "FILE",
+        conflicts_with_all = ["dry_run", "journal"]
+    )]
+    script: Option<PathBuf>,
+    /// Ask what to do with each duplicate before applying any action
+    #[arg(
+        short('i'),
+        long("interactive"),
+        conflicts_with_all = ["dry_run", "script"]
+    )]
+    interactive: bool,
+    /// Record applied actions to a journal, so they can be reverted with `dedup undo`
+    #[arg(short('j'), long("journal"), value_name = "FILE")]
+    journal: Option<PathBuf>,
+    /// Output format of the duplicate report
+    #[arg(
+        short('f'),
+        long("format"),
+        value_enum,
+        default_value_t = Format::Text
+    )]
+    format: Format,
+    /// Export the report to a CSV file
+    #[arg(long("csv"), value_name = "FILE", conflicts_with = "tsv")]
+    csv: Option<PathBuf>,
+    /// Export the report to a TSV file
+    #[arg(long("tsv"), value_name = "FILE")]
+    tsv: Option<PathBuf>,
+}
+
+impl RunOptions {
+    /// Fails early if a script is requested for an action which cannot be scripted
+    fn check(&self, action: &ActionOptions) -> io::Result<()> {
+        match self.script {
+            Some(_) => check_scriptable(action.action),
+            None => Ok(()),
+        }
+    }
+
+    fn reporter(&self) -> io::Result<Reporter> {
+        let table = match (&self.csv, &self.tsv) {
+            (Some(path), _) => Some(TableWriter::create(path, Separator::Comma)?),
+            (None, Some(path)) => Some(TableWriter::create(path, Separator::Tab)?),
+            (None, None) => None,
+        };
+        Ok(Reporter::new(self.format, table))
+    }
 }
 
-/// Returns a list of files in a directory
+/// Strategy for selecting reference candidates for a target file
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum MatchMode {
+    /// Compare only files with the same name
+    Name,
+    /// Compare all files with the same size, regardless of their names
+    Content,
+}
+
+/// Returns a sorted list of files in a directory
+///
+/// The directory is walked by as many threads as the current thread pool has.
 ///
 /// # Arguments
 /// * `path` - A path to a directory
-fn scan_dir(path: impl AsRef<Path>) -> io::Result<Vec<PathBuf>> {
-    let mut items = Vec::new();
-    for entry in path.as_ref().read_dir()? {
-        let path = entry?.path();
-        if path.is_dir() {
-            let dir_items = scan_dir(path)?;
-            items.extend(dir_items);
-        } else if path.is_file() && !path.is_symlink() {
-            items.push(path);
-        }
+/// * `filter` - A filter selecting files by their paths relative to `path`
+fn scan_dir(path: impl AsRef<Path>, filter: &Filter) -> io::Result<Vec<PathBuf>> {
+    let root = path.as_ref();
+    let items = Mutex::new(Vec::new());
+    let error = Mutex::new(None);
+    let walker = filter
+        .walker(root)
+        .threads(rayon::current_num_threads())
+        .build_parallel();
+    walker.run(|| {
+        Box::new(|entry| {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    let kind = e.io_error().map_or(io::ErrorKind::Other, io::Error::kind);
+                    *error.lock().unwrap() = Some(io::Error::new(kind, e));
+                    return WalkState::Quit;
+                }
+            };
+            let is_file = entry
+                .file_type()
+                .is_some_and(|file_type| file_type.is_file());
+            if is_file
+                && !entry.path_is_symlink()
+                && filter.is_included(entry.path().strip_prefix(root).unwrap())
+            {
+                items.lock().unwrap().push(entry.into_path());
+            }
+            WalkState::Continue
+        })
+    });
+    if let Some(e) = error.into_inner().unwrap() {
+        return Err(e);
     }
+    let mut items = items.into_inner().unwrap();
+    items.sort();
     Ok(items)
 }
 
-/// Compare two files
-/// # Arguments
-/// * `path1` - A path to a file
-/// * `path2` - A path to a file
-/// # Returns
-/// * `Ok(true)` if the files are the same
-/// * `Ok(false)` if the files are different
-/// * `Err` if the comparison failed
-fn compare_files(path1: impl AsRef<Path>, path2: impl AsRef<Path>) -> io::Result<bool> {
-    let path1 = path1.as_ref();
-    let path2 = path2.as_ref();
-
-    let meta1 = path1.metadata()?;
-    let meta2 = path2.metadata()?;
-    if meta1.len() != meta2.len() {
-        return Ok(false);
+/// Finds reference files matching each of the target files
+fn match_targets(reference: &ReferenceData, target_files: Vec<PathBuf>) -> io::Result<Matches> {
+    let found = target_files
+        .par_iter()
+        .map(|target_file| {
+            let found = reference.find_duplicate(target_file)?;
+            let hash = match found {
+                Some(Match::Duplicate(_)) => reference.known_hash(target_file)?,
+                _ => None,
+            };
+            Ok((found, hash))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut matches = Matches::default();
+    for (target_file, (found, hash)) in target_files.into_iter().zip(found) {
+        match found {
+            Some(Match::Duplicate(ref_file)) => {
+                if let Some(hash) = hash {
+                    matches.hashes.insert(target_file.clone(), hash);
+                }
+                matches.duplicates.push((target_file, ref_file.to_owned()))
+            }
+            Some(Match::Linked(ref_file)) => {
+                matches.linked.push((target_file, ref_file.to_owned()))
+            }
+            None => {}
+        }
     }
-    let len = meta1.len();
+    Ok(matches)
+}
+
+/// States of scanned files by their paths
+type States = HashMap<PathBuf, FileState>;
 
-    let mut f1 = File::open(path1)?;
-    let mut f2 = File::open(path2)?;
+/// Checksums of reference files which cannot be read, by their paths
+type Checksums = HashMap<PathBuf, Checksum>;
 
-    const BUFFER_SIZE: usize = 4096;
-    let mut buffer1 = [0; BUFFER_SIZE];
-    let mut buffer2 = [0; BUFFER_SIZE];
+/// Captures the state of files before they are compared
+fn capture_states(paths: &[PathBuf]) -> io::Result<States> {
+    paths
+        .par_iter()
+        .map(|path| Ok((path.clone(), FileState::capture(path)?)))
+        .collect()
+}
 
-    let buffer_count = len / BUFFER_SIZE as u64;
-    for _ in 0..buffer_count {
-        f1.read_exact(&mut buffer1)?;
-        f2.read_exact(&mut buffer2)?;
-        if buffer1 != buffer2 {
-            return Ok(false);
+/// Checks that a duplicate and its reference file have not changed since they were compared
+///
+/// A reference file known only by its checksum is never read, so the
+/// duplicate is compared with the checksum instead, and actions needing
+/// the reference file and byte by byte comparison are refused.
+///
+/// # Arguments
+/// * `states` - States of the files captured before they were compared
+/// * `checksums` - Checksums of reference files which cannot be read
+/// * `record` - The duplicate, its reference file and the action to be applied
+/// * `recheck` - How the content of both files is compared again
+///
+/// # Returns
+/// * A reason to skip the pair, if any
+fn verify_pair(
+    states: &States,
+    checksums: &Checksums,
+    record: &Record,
+    recheck: Recheck,
+) -> io::Result<Option<String>> {
+    let (target, reference) = (&record.target, &record.reference);
+    if let Some(reason) = states[target].check(None)? {
+        return Ok(Some(reason));
+    }
+    let matches = match checksums.get(reference) {
+        Some(_) if record.action.needs_reference() || recheck == Recheck::Bytes => {
+            return Ok(Some(
+                "reference file is known only by its checksum".to_owned(),
+            ))
         }
+        Some(_) if recheck == Recheck::None => true,
+        Some(checksum) => checksum.algorithm.hash_file(target, None)?.to_string() == checksum.hash,
+        None => {
+            if let Some(reason) = states[reference].check(None)? {
+                return Ok(Some(reason));
+            }
+            match recheck {
+                Recheck::None => true,
+                Recheck::Hash(algorithm) => {
+                    algorithm.hash_file(target, None)? == algorithm.hash_file(reference, None)?
+                }
+                Recheck::Bytes => compare_files(target, reference)?,
+            }
+        }
+    };
+    if !matches {
+        return Ok(Some(format!(
+            "content of {target:?} no longer matches the reference file"
+        )));
     }
+    Ok(None)
+}
 
-    let mut buffer1 = vec![];
-    let mut buffer2 = vec![];
-    f1.read_to_end(&mut buffer1)?;
-    f2.read_to_end(&mut buffer2)?;
-    if buffer1 != buffer2 {
-        return Ok(false);
+/// Applies the selected action to duplicate files
+///
+/// # Arguments
+/// * `matches` - Pairs of duplicates and their reference files, and of links
+///   to reference files, which are only reported
+/// * `roots` - Paths to the deduplicated directories containing the duplicates
+/// * `action` - The action applied to the duplicates
+/// * `run` - Options controlling how the action is applied and reported
+/// * `reporter` - A reporter receiving the outcome for each duplicate
+/// * `algorithm` - The algorithm of hashes in the report and the journal
+/// * `check` - Returns a reason to skip a duplicate, if any. It is called
+///   before the duplicate is reported and again right before an action is
+///   applied, if the user was asked about it in between.
+fn process_duplicates(
+    matches: Matches,
+    roots: &[PathBuf],
+    action: &ActionOptions,
+    run: &RunOptions,
+    mut reporter: Reporter,
+    algorithm: Algorithm,
+    mut check: impl FnMut(&Record) -> io::Result<Option<String>>,
+) -> io::Result<()> {
+    let mut journal = match &run.journal {
+        Some(path) if !run.dry_run => Some(Journal::open(path)?),
+        _ => None,
+    };
+    let mut script = match &run.script {
+        Some(path) => Some(ScriptWriter::create(path, action.action)?),
+        None => None,
+    };
+    let mut prompt = run.interactive.then(|| {
+        let link = match action.action {
+            Action::Symlink | Action::Reflink => action.action,
+            _ => Action::Hardlink,
+        };
+        Prompt::terminal(action.action, link)
+    });
+    // Duplicates left after the user quits are still reported as skipped
+    let mut quit = false;
+    for (link, ref_file) in matches.linked {
+        reporter.record(Record::new(link, ref_file, action.action, Outcome::Linked))?;
+    }
+    for (target_file, ref_file) in matches.duplicates {
+        let mut record = Record::new(target_file, ref_file, action.action, Outcome::DryRun);
+        let skip_reason = if quit {
+            Some("quit by the user".to_owned())
+        } else {
+            check(&record)?
+        };
+        if skip_reason.is_none() && (reporter.needs_hashes() || journal.is_some()) {
+            let hash = match matches.hashes.get(&record.target) {
+                Some(hash) => *hash,
+                None => algorithm.hash_file(&record.target, None)?,
+            };
+            record.algorithm = Some(algorithm);
+            record.hash = Some(hash.to_string());
+        }
+        reporter.found(&record);
+        if let Some(reason) = skip_reason {
+            record.outcome = Outcome::Skipped;
+            record.message = Some(reason);
+            reporter.record(record)?;
+            continue;
+        }
+        if run.dry_run {
+            reporter.record(record)?;
+            continue;
+        }
+        let mut action = action.clone();
+        if let Some(prompt) = &mut prompt {
+            let reason = match prompt.ask(&record)? {
+                Choice::Apply(chosen) => {
+                    action.action = chosen;
+                    record.action = chosen;
+                    None
+                }
+                Choice::Keep => Some("kept by the user"),
+                Choice::Skip => Some("skipped by the user"),
+                Choice::Quit => {
+                    quit = true;
+                    Some("quit by the user")
+                }
+            };
+            let reason = match reason {
+                Some(reason) => Some(reason.to_owned()),
+                None => check(&record)?,
+            };
+            if let Some(reason) = reason {
+                record.outcome = Outcome::Skipped;
+                record.message = Some(reason);
+                reporter.record(record)?;
+                continue;
+            }
+        }
+        let root = roots
+            .iter()
+            .find(|root| record.target.starts_with(root))
+            .unwrap();
+        if let Some(script) = &mut script {
+            script.write(&action, &record.target, &record.reference, root)?;
+            record.outcome = Outcome::Scripted;
+            reporter.record(record)?;
+            continue;
+        }
+        let entry = match (&journal, &record.hash) {
+            (Some(_), Some(hash)) => Some(JournalEntry::new(
+                action.action,
+                &record.target,
+                &record.reference,
+                algorithm,
+                hash.clone(),
+            )?),
+            _ => None,
+        };
+        // The entry is written before the action, so a run killed in between
+        // leaves no change unrecorded
+        let result = action.apply(
+            &record.target,
+            &record.reference,
+            root,
+            |destination| match (&mut journal, entry) {
+                (Some(journal), Some(mut entry)) => {
+                    entry.destination = destination.map(std::path::absolute).transpose()?;
+                    journal.append(&entry)
+                }
+                _ => Ok(()),
+            },
+        );
+        match result {
+            Ok(_) => {
+                record.outcome = Outcome::Done;
+                reporter.record(record)?;
+            }
+            Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                record.outcome = Outcome::Skipped;
+                record.message = Some("reference file is on another filesystem".to_owned());
+                reporter.record(record)?;
+            }
+            Err(e) => {
+                record.outcome = Outcome::Failed;
+                record.message = Some(e.to_string());
+                reporter.record(record)?;
+                reporter.finish()?;
+                return Err(e);
+            }
+        }
+    }
+    if let Some(script) = &mut script {
+        script.flush()?;
     }
+    reporter.finish()
+}
 
-    Ok(true)
+/// Scans reference and target directories and finds duplicates
+///
+/// Reference files saved in indexes are matched by their saved hashes.
+/// States of indexed files are taken from the indexes, so a reference file
+/// changed since it was indexed is skipped when its duplicate is processed.
+/// Files listed in checksum manifests are compared like files of reference
+/// directories if they can be read, and only by their checksums otherwise.
+///
+/// # Returns
+/// * Duplicates and links to reference files
+/// * States of all scanned files, captured before they were compared
+/// * Checksums of the reference files which cannot be read
+fn scan_and_compare(
+    dirs: &Dirs,
+    scan: &ScanOptions,
+    reporter: &Reporter,
+) -> io::Result<(Matches, States, Checksums)> {
+    let mut indexes = Vec::new();
+    for path in &dirs.indexes {
+        reporter.progress(&format!("Reading index {path:?}..."));
+        let index = Index::read(path)?;
+        if index.algorithm != scan.walk.hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "index {path:?} holds {} hashes, use --hash {}",
+                    index.algorithm, index.algorithm
+                ),
+            ));
+        }
+        indexes.push(index);
+    }
+    let mut listed = Vec::new();
+    let mut checksums = Checksums::new();
+    for path in &dirs.manifests {
+        reporter.progress(&format!("Reading manifest {path:?}..."));
+        for (file, checksum) in Manifest::read(path, dirs.manifest_hash)?.entries {
+            if file.is_file() {
+                listed.push(file);
+            } else {
+                checksums.insert(file, checksum);
+            }
+        }
+    }
+    let references = dirs.references();
+    let targets = dirs.targets();
+    // Indexed directories which are not mounted are only told apart by inode
+    let indexed_roots: Vec<PathBuf> = indexes
+        .iter()
+        .map(|index| index.root.clone())
+        .filter(|root| root.is_dir())
+        .collect();
+    check_roots(&[references.as_slice(), &indexed_roots].concat(), &targets)?;
+    let walk = &scan.walk;
+    let filter = walk.filter()?;
+    let cache = walk.cache()?;
+    let result = walk.thread_pool()?.install(|| {
+        let mut ref_contents = Vec::new();
+        for reference in &references {
+            reporter.progress(&format!("Scanning reference directory {reference:?}..."));
+            ref_contents.extend(scan_dir(reference, &filter)?);
+        }
+        ref_contents.extend(listed);
+        let mut target_contents = Vec::new();
+        for target in &targets {
+            reporter.progress(&format!("Scanning target directory {target:?}..."));
+            target_contents.extend(scan_dir(target, &filter)?);
+        }
+        let mut states = capture_states(&ref_contents)?;
+        states.extend(capture_states(&target_contents)?);
+        let inodes = walk.inodes(&cache);
+        let mut reference =
+            ReferenceData::new(ref_contents, scan.match_mode(MatchMode::Name), inodes)?;
+        for index in indexes {
+            for entry in &index.entries {
+                let state = FileState {
+                    hash: None,
+                    ..entry.state.clone()
+                };
+                states.insert(state.path.clone(), state);
+            }
+            reference.add_index(index)?;
+        }
+        for (file, checksum) in &checksums {
+            reference.add_checksum(file.clone(), checksum.clone());
+        }
+        reporter.progress("Comparing files...");
+        let matches = match_targets(&reference, target_contents)?;
+        Ok((matches, states, checksums))
+    });
+    if let Some(cache) = cache {
+        cache.save()?;
+    }
+    result
 }
 
-struct ReferenceData {
-    files: HashMap<OsString, Vec<PathBuf>>,
+fn dedup(dirs: &Dirs, options: &Options) -> io::Result<()> {
+    options.run.check(&options.action)?;
+    let reporter = options.run.reporter()?;
+    let (matches, states, checksums) = scan_and_compare(dirs, &options.scan, &reporter)?;
+    process_duplicates(
+        matches,
+        &dirs.targets(),
+        &options.action,
+        &options.run,
+        reporter,
+        options.scan.walk.hash,
+        |record| verify_pair(&states, &checksums, record, options.recheck()),
+    )
 }
 
-impl ReferenceData {
-    fn new(paths: Vec<PathBuf>) -> Self {
-        let mut files = HashMap::with_capacity(paths.len());
-        for path in paths {
-            let file_name = path.file_name().unwrap().to_owned();
-            let entry = files.entry(file_name).or_insert_with(Vec::new);
-            entry.push(path);
+fn dedup_self(
+    roots: &[PathBuf],
+    keep: KeepPolicy,
+    prefer: Vec<Glob>,
+    options: &Options,
+) -> io::Result<()> {
+    check_roots(&[], roots)?;
+    options.run.check(&options.action)?;
+    let reporter = options.run.reporter()?;
+    let walk = &options.scan.walk;
+    let filter = walk.filter()?;
+    let cache = walk.cache()?;
+    let result = walk.thread_pool()?.install(|| {
+        let mut contents = Vec::new();
+        for root in roots {
+            reporter.progress(&format!("Scanning directory {root:?}..."));
+            contents.extend(scan_dir(root, &filter)?);
         }
-        Self { files }
+        let states = capture_states(&contents)?;
+        reporter.progress("Comparing files...");
+        let matches = find_duplicates_within(
+            contents,
+            options.scan.match_mode(MatchMode::Content),
+            keep,
+            prefer,
+            walk.inodes(&cache),
+        )?;
+        io::Result::Ok((matches, states))
+    });
+    if let Some(cache) = cache {
+        cache.save()?;
     }
+    let (matches, states) = result?;
+    process_duplicates(
+        matches,
+        roots,
+        &options.action,
+        &options.run,
+        reporter,
+        walk.hash,
+        |record| verify_pair(&states, &Checksums::new(), record, options.recheck()),
+    )
+}
 
-    fn find_duplicate(&self, file: impl AsRef<Path>) -> io::Result<Option<&Path>> {
-        let file = file.as_ref();
-        let file_name = file.file_name().unwrap().to_owned();
-        if let Some(candidates) = self.files.get(&file_name) {
-            for candidate in candidates {
-                if compare_files(file, candidate)? {
-                    return Ok(Some(candidate));
-                }
-            }
+fn make_plan(
+    dirs: &Dirs,
+    scan: &ScanOptions,
+    mut action: ActionOptions,
+    output: &Path,
+) -> io::Result<()> {
+    // Like all other paths in the plan, so it can be applied from any directory
+    action.quarantine = action.quarantine.map(std::path::absolute).transpose()?;
+    let reporter = Reporter::new(Format::Text, None);
+    let targets = dirs.targets();
+    let (matches, states, checksums) = scan_and_compare(dirs, scan, &reporter)?;
+    let mut entries = Vec::with_capacity(matches.duplicates.len());
+    for (target_file, ref_file) in matches.duplicates {
+        if checksums.contains_key(&ref_file) {
+            println!("Skipping {target_file:?}: reference file {ref_file:?} is known only by its checksum");
+            continue;
         }
-        Ok(None)
+        let hash = match matches.hashes.get(&target_file) {
+            Some(hash) => hash.to_string(),
+            None => scan.walk.hash.hash_file(&target_file, None)?.to_string(),
+        };
+        let root = targets
+            .iter()
+            .find(|root| target_file.starts_with(root))
+            .unwrap();
+        entries.push(PlanEntry {
+            target: FileState {
+                hash: Some(hash.clone()),
+                ..states[&target_file].clone()
+            },
+            reference: FileState {
+                hash: Some(hash),
+                ..states[&ref_file].clone()
+            },
+            root: std::path::absolute(root)?,
+        });
+    }
+    println!(
+        "Writing a plan of {} actions to {output:?}...",
+        entries.len()
+    );
+    Plan {
+        action,
+        algorithm: scan.walk.hash,
+        entries,
     }
+    .write(output)
 }
 
-fn find_duplicates(
-    reference_files: Vec<PathBuf>,
-    target_files: Vec<PathBuf>,
-) -> io::Result<Vec<(PathBuf, PathBuf)>> {
-    let reference = ReferenceData::new(reference_files);
+fn make_index(reference: &Path, walk: &WalkOptions, output: &Path) -> io::Result<()> {
+    if !reference.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotADirectory,
+            format!("{reference:?} is not a directory"),
+        ));
+    }
+    let filter = walk.filter()?;
+    let cache = walk.cache()?;
+    let result = walk.thread_pool()?.install(|| {
+        println!("Scanning reference directory {reference:?}...");
+        let paths = scan_dir(reference, &filter)?;
+        println!("Hashing {} files...", paths.len());
+        Index::build(reference, paths, &walk.inodes(&cache))
+    });
+    if let Some(cache) = cache {
+        cache.save()?;
+    }
+    let index = result?;
+    println!(
+        "Writing an index of {} files to {output:?}...",
+        index.entries.len()
+    );
+    index.write(output)
+}
 
-    let mut duplicates = Vec::new();
-    for target_file in target_files {
-        if let Some(ref_file) = reference.find_duplicate(&target_file)? {
-            duplicates.push((target_file, ref_file.to_owned()));
+fn apply_plan(path: impl AsRef<Path>, run: &RunOptions) -> io::Result<()> {
+    let plan = Plan::read(path)?;
+    run.check(&plan.action)?;
+    let mut roots: Vec<PathBuf> = plan
+        .entries
+        .iter()
+        .map(|entry| entry.root.clone())
+        .collect();
+    roots.sort();
+    roots.dedup();
+    let duplicates = plan
+        .entries
+        .iter()
+        .map(|entry| (entry.target.path.clone(), entry.reference.path.clone()))
+        .collect();
+    let entries: HashMap<&Path, &PlanEntry> = plan
+        .entries
+        .iter()
+        .map(|entry| (entry.target.path.as_path(), entry))
+        .collect();
+    // Targets are checked against their planned hashes before they are processed
+    let mut hashes = HashMap::new();
+    for entry in &plan.entries {
+        if let Some(hash) = &entry.target.hash {
+            hashes.insert(entry.target.path.clone(), parse_digest(hash)?);
         }
     }
-    Ok(duplicates)
+    let matches = Matches {
+        duplicates,
+        linked: Vec::new(),
+        hashes,
+    };
+    process_duplicates(
+        matches,
+        &roots,
+        &plan.action,
+        run,
+        run.reporter()?,
+        plan.algorithm,
+        |record| {
+            let entry = entries[record.target.as_path()];
+            let content = Some(plan.algorithm);
+            Ok(entry
+                .target
+                .check(content)?
+                .or(entry.reference.check(content)?))
+        },
+    )
 }
 
-fn dedup(reference: impl AsRef<Path>, target: impl AsRef<Path>, dry_run: bool) -> io::Result<()> {
-    println!("Scanning reference directory...");
-    let ref_contents = scan_dir(&reference)?;
-    println!("Scanning target directory...");
-    let target_contents = scan_dir(&target)?;
-    println!("Comparing files...");
-    let duplicates = find_duplicates(ref_contents, target_contents)?;
-    for (target_file, ref_file) in duplicates {
-        println!("Duplicate found: {target_file:?} -> {ref_file:?}");
-        if !dry_run {
-            fs::remove_file(target_file)?;
+fn undo_journal(path: impl AsRef<Path>, dry_run: bool) -> io::Result<()> {
+    let entries = read_journal(path)?;
+    let mut failed = 0;
+    for entry in entries.iter().rev() {
+        println!(
+            "Undoing {:?}: {:?} -> {:?}",
+            entry.action, entry.target, entry.reference
+        );
+        if dry_run {
+            continue;
         }
+        match undo(entry) {
+            Ok(true) => {}
+            Ok(false) => println!("Nothing to undo, {:?} was left untouched", entry.target),
+            Err(e) => {
+                eprintln!("Failed to undo {:?}: {}", entry.target, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(io::Error::other(format!(
+            "{failed} of {} actions could not be undone",
+            entries.len()
+        )));
     }
     Ok(())
 }
 
 fn main() -> ExitCode {
     let args = Args::parse();
-    println!("{:?}", args);
 
-    if let Err(e) = dedup(args.reference, args.target, args.dry_run) {
+    let result = match &args.command {
+        Some(Command::SelfDedup {
+            options,
+            keep,
+            prefer,
+            roots,
+        }) => dedup_self(roots, *keep, prefer.clone(), options),
+        Some(Command::Plan {
+            scan,
+            action,
+            output,
+            dirs,
+        }) => make_plan(dirs, scan, action.clone(), output),
+        Some(Command::Index {
+            walk,
+            output,
+            reference,
+        }) => make_index(reference, walk, output),
+        Some(Command::Apply { run, plan }) => apply_plan(plan, run),
+        Some(Command::Undo { dry_run, journal }) => undo_journal(journal, *dry_run),
+        None => dedup(&args.dirs, &args.options),
+    };
+    if let Err(e) = result {
         eprintln!("Error: {}", e);
         ExitCode::FAILURE
     } else {
@@ -160,9 +985,19 @@ mod tests {
     use super::*;
     use rand::Rng;
     use std::fs;
+    use std::fs::File;
     use std::io::Write;
     use tempdir::TempDir;
 
+    fn find_duplicates(
+        reference_files: Vec<PathBuf>,
+        target_files: Vec<PathBuf>,
+        mode: MatchMode,
+    ) -> io::Result<Matches> {
+        let reference = ReferenceData::new(reference_files, mode, Inodes::default())?;
+        match_targets(&reference, target_files)
+    }
+
     fn create_file(path: impl AsRef<Path>) {
         let mut rng = rand::thread_rng();
         let size: usize = rng.gen_range(0..=1024);
@@ -186,7 +1021,7 @@ mod tests {
         fs::create_dir(tmp_path.join("dir1").join("dir2")).unwrap();
         create_file(tmp_path.join("dir1").join("dir2").join("file3"));
 
-        let mut files = scan_dir(tmp_path).unwrap();
+        let mut files = scan_dir(tmp_path, &Filter::default()).unwrap();
         files.sort();
         assert_eq!(
             files,
@@ -198,6 +1033,63 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_scan_dir_filter() {
+        let tmp = TempDir::new("test_scan_dir_filter").unwrap();
+        let tmp_path = tmp.path();
+
+        fs::create_dir_all(tmp_path.join(".git").join("objects")).unwrap();
+        fs::create_dir_all(tmp_path.join("src").join(".git")).unwrap();
+        create_file(tmp_path.join(".git").join("objects").join("file.rs"));
+        create_file(tmp_path.join("src").join(".git").join("file.rs"));
+        create_file(tmp_path.join("src").join("main.rs"));
+        create_file(tmp_path.join("README.md"));
+
+        let filter = Filter::new(
+            vec![parse_glob("**/*.rs").unwrap()],
+            vec![parse_glob(".git").unwrap()],
+            false,
+        )
+        .unwrap();
+        let mut files = scan_dir(tmp_path, &filter).unwrap();
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                tmp_path.join("src").join(".git").join("file.rs"),
+                tmp_path.join("src").join("main.rs"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scan_dir_ignore_files() {
+        let tmp = TempDir::new("test_scan_dir_ignore_files").unwrap();
+        let tmp_path = tmp.path();
+
+        fs::create_dir_all(tmp_path.join("sub").join("build")).unwrap();
+        fs::write(tmp_path.join(".gitignore"), "*.log\n!keep.log\n").unwrap();
+        fs::write(tmp_path.join("sub").join(".dedupignore"), "build/\n").unwrap();
+        create_file(tmp_path.join("debug.log"));
+        create_file(tmp_path.join("keep.log"));
+        create_file(tmp_path.join("sub").join("build").join("output"));
+        create_file(tmp_path.join("sub").join("source"));
+
+        let filter = Filter::new(vec![], vec![], true).unwrap();
+        let mut files = scan_dir(tmp_path, &filter).unwrap();
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                tmp_path.join(".gitignore"),
+                tmp_path.join("keep.log"),
+                tmp_path.join("sub").join(".dedupignore"),
+                tmp_path.join("sub").join("source"),
+            ]
+        );
+        assert_eq!(scan_dir(tmp_path, &Filter::default()).unwrap().len(), 6);
+    }
+
     #[test]
     fn test_find_duplicates() {
         let tmp = TempDir::new("test_find_duplicates").unwrap();
@@ -214,7 +1106,7 @@ mod tests {
         create_file(ref_dir.join("file3"));
         create_file(ref_dir.join("file4"));
         create_file(ref_dir.join("file5"));
-        let ref_files = scan_dir(&ref_dir).unwrap();
+        let ref_files = scan_dir(&ref_dir, &Filter::default()).unwrap();
 
         create_file(target_dir.join("file1"));
         create_file(target_dir.join("file3"));
@@ -222,9 +1114,11 @@ mod tests {
         create_file(target_dir.join("file6"));
         fs::copy(ref_dir.join("dir2").join("file2"), target_dir.join("file2")).unwrap();
         fs::copy(ref_dir.join("file4"), target_dir.join("file4")).unwrap();
-        let target_files = scan_dir(&target_dir).unwrap();
+        let target_files = scan_dir(&target_dir, &Filter::default()).unwrap();
 
-        let mut duplicates = find_duplicates(ref_files, target_files).unwrap();
+        let mut duplicates = find_duplicates(ref_files, target_files, MatchMode::Name)
+            .unwrap()
+            .duplicates;
         duplicates.sort();
         assert_eq!(
             duplicates,
@@ -234,4 +1128,199 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_find_duplicates_by_content() {
+        let tmp = TempDir::new("test_find_duplicates_by_content").unwrap();
+        let tmp_path = tmp.path();
+
+        let ref_dir = tmp_path.join("ref");
+        let target_dir = tmp_path.join("target");
+        fs::create_dir(&ref_dir).unwrap();
+        fs::create_dir(&target_dir).unwrap();
+
+        create_file(ref_dir.join("IMG_0001.jpg"));
+        create_file(ref_dir.join("IMG_0002.jpg"));
+        let ref_files = scan_dir(&ref_dir, &Filter::default()).unwrap();
+
+        create_file(target_dir.join("IMG_0002.jpg"));
+        fs::copy(ref_dir.join("IMG_0001.jpg"), target_dir.join("holiday.jpg")).unwrap();
+        let target_files = scan_dir(&target_dir, &Filter::default()).unwrap();
+
+        let duplicates =
+            find_duplicates(ref_files.clone(), target_files.clone(), MatchMode::Name).unwrap();
+        assert!(duplicates.duplicates.is_empty());
+
+        let duplicates = find_duplicates(ref_files, target_files, MatchMode::Content).unwrap();
+        assert_eq!(
+            duplicates.duplicates,
+            [(target_dir.join("holiday.jpg"), ref_dir.join("IMG_0001.jpg"))]
+        );
+        let hash = Algorithm::default()
+            .hash_file(ref_dir.join("IMG_0001.jpg"), None)
+            .unwrap();
+        assert_eq!(
+            duplicates.hashes,
+            HashMap::from([(target_dir.join("holiday.jpg"), hash)])
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_plan_relative_quarantine() {
+        let tmp = TempDir::new("test_plan_relative_quarantine").unwrap();
+        let tmp_path = tmp.path();
+        let plan_path = tmp_path.join("plan.json");
+        for dir in ["ref", "target"] {
+            fs::create_dir(tmp_path.join(dir)).unwrap();
+            fs::write(tmp_path.join(dir).join("file"), "content").unwrap();
+        }
+        // A path to the quarantine directory relative to the current directory
+        let cwd = std::env::current_dir().unwrap();
+        let quarantine: PathBuf = cwd
+            .components()
+            .skip(1)
+            .map(|_| std::path::Component::ParentDir)
+            .collect::<PathBuf>()
+            .join(tmp_path.strip_prefix("/").unwrap())
+            .join("quarantine");
+
+        let args = Args::parse_from([
+            Path::new("dedup"),
+            Path::new("plan"),
+            Path::new("--action=move"),
+            Path::new("--quarantine"),
+            &quarantine,
+            Path::new("-o"),
+            &plan_path,
+            &tmp_path.join("ref"),
+            &tmp_path.join("target"),
+        ]);
+        let Some(Command::Plan {
+            scan,
+            action,
+            output,
+            dirs,
+        }) = args.command
+        else {
+            unreachable!()
+        };
+        make_plan(&dirs, &scan, action, &output).unwrap();
+        let plan = Plan::read(&plan_path).unwrap();
+        assert!(plan.action.quarantine.unwrap().is_absolute());
+
+        let args = Args::parse_from([Path::new("dedup"), Path::new("apply"), &plan_path]);
+        let Some(Command::Apply { run, plan }) = args.command else {
+            unreachable!()
+        };
+        apply_plan(plan, &run).unwrap();
+        assert!(!tmp_path.join("target").join("file").exists());
+        assert!(tmp_path.join("quarantine").join("file").exists());
+    }
+
+    #[test]
+    fn test_deterministic_order() {
+        let tmp = TempDir::new("test_deterministic_order").unwrap();
+        let tmp_path = tmp.path();
+
+        let ref_dir = tmp_path.join("ref");
+        let target_dir = tmp_path.join("target");
+        for i in 0..20 {
+            let dir = format!("dir{}", i % 4);
+            fs::create_dir_all(ref_dir.join(&dir)).unwrap();
+            fs::create_dir_all(target_dir.join(&dir)).unwrap();
+            create_file(ref_dir.join(&dir).join(format!("file{i}")));
+            fs::copy(
+                ref_dir.join(&dir).join(format!("file{i}")),
+                target_dir.join(&dir).join(format!("file{i}")),
+            )
+            .unwrap();
+        }
+
+        let run = |jobs| {
+            let args = Args::try_parse_from([
+                "dedup".as_ref(),
+                "--match".as_ref(),
+                "content".as_ref(),
+                "--jobs".as_ref(),
+                jobs,
+                ref_dir.as_os_str(),
+                target_dir.as_os_str(),
+            ])
+            .unwrap();
+            let reporter = Reporter::new(Format::Json, None);
+            let (matches, _, _) =
+                scan_and_compare(&args.dirs, &args.options.scan, &reporter).unwrap();
+            matches.duplicates
+        };
+        let duplicates = run("1".as_ref());
+        assert_eq!(duplicates.len(), 20);
+        for _ in 0..5 {
+            assert_eq!(run("8".as_ref()), duplicates);
+        }
+    }
+
+    #[test]
+    fn test_index_reference() {
+        let tmp = TempDir::new("test_index_reference").unwrap();
+        let ref_dir = tmp.path().join("ref");
+        let target_dir = tmp.path().join("target");
+        let index_path = tmp.path().join("ref.idx");
+        fs::create_dir(&ref_dir).unwrap();
+        fs::create_dir(&target_dir).unwrap();
+        create_file(ref_dir.join("file1"));
+        create_file(ref_dir.join("file2"));
+        fs::copy(ref_dir.join("file1"), target_dir.join("copy")).unwrap();
+
+        let paths = scan_dir(&ref_dir, &Filter::default()).unwrap();
+        let index = Index::build(&ref_dir, paths, &Inodes::default()).unwrap();
+        index.write(&index_path).unwrap();
+        // The reference directory is never read again
+        fs::write(ref_dir.join("file1"), "changed").unwrap();
+
+        let args = Args::try_parse_from([
+            "dedup".as_ref(),
+            "--match".as_ref(),
+            "content".as_ref(),
+            "--index".as_ref(),
+            index_path.as_os_str(),
+            "-t".as_ref(),
+            target_dir.as_os_str(),
+        ])
+        .unwrap();
+        let reporter = Reporter::new(Format::Json, None);
+        let (matches, states, checksums) =
+            scan_and_compare(&args.dirs, &args.options.scan, &reporter).unwrap();
+        let ref_file = std::path::absolute(ref_dir.join("file1")).unwrap();
+        assert_eq!(
+            matches.duplicates,
+            [(target_dir.join("copy"), ref_file.clone())]
+        );
+        let record = Record::new(
+            target_dir.join("copy"),
+            ref_file,
+            Action::Delete,
+            Outcome::DryRun,
+        );
+        assert!(verify_pair(&states, &checksums, &record, Recheck::None)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_multiple_directories() {
+        let args =
+            Args::try_parse_from(["dedup", "-r", "ref1", "-t", "tgt1", "-r", "ref2"]).unwrap();
+        assert_eq!(
+            args.dirs.references(),
+            [Path::new("ref1"), Path::new("ref2")]
+        );
+        assert_eq!(args.dirs.targets(), [Path::new("tgt1")]);
+
+        let args = Args::try_parse_from(["dedup", "ref1", "tgt1", "-t", "tgt2"]).unwrap();
+        assert_eq!(args.dirs.references(), [Path::new("ref1")]);
+        assert_eq!(args.dirs.targets(), [Path::new("tgt1"), Path::new("tgt2")]);
+
+        assert!(Args::try_parse_from(["dedup", "-r", "ref1"]).is_err());
+    }
 }
@@ -0,0 +1,168 @@
+use crate::hash::Algorithm;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+/// A checksum of a file listed in a manifest
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Checksum {
+    pub algorithm: Algorithm,
+    /// The checksum as a lowercase hexadecimal string
+    pub hash: String,
+}
+
+/// Files listed in a checksum manifest
+#[derive(Debug)]
+pub struct Manifest {
+    pub entries: Vec<(PathBuf, Checksum)>,
+}
+
+impl Manifest {
+    /// Reads a manifest written by `sha256sum`, `b3sum`, `xxh128sum` or `md5sum`, or in the BSD style
+    ///
+    /// Relative paths are resolved against the directory containing the manifest.
+    ///
+    /// # Arguments
+    /// * `path` - A path to the manifest
+    /// * `algorithm` - The algorithm of lines not naming one. Such lines are
+    ///   refused without it, since checksums of MD5 and XXH3 and of SHA-256
+    ///   and BLAKE3 have the same lengths.
+    pub fn read(path: impl AsRef<Path>, algorithm: Option<Algorithm>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let mut entries = Vec::new();
+        for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            let line = line.strip_suffix('\r').unwrap_or(&line);
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |message: &str| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{path:?} line {}: {message}", number + 1),
+                )
+            };
+            let (tag, name, hash) =
+                parse_line(line).ok_or_else(|| error("invalid checksum line"))?;
+            let algorithm = match tag {
+                Some(tag) => Algorithm::from_tag(tag)
+                    .ok_or_else(|| error(&format!("unsupported hash algorithm {tag}")))?,
+                None => algorithm.ok_or_else(|| {
+                    error("cannot tell the hash algorithm, specify it with --manifest-hash")
+                })?,
+            };
+            if hash.len() != 2 * algorithm.digest_len()
+                || !hash.bytes().all(|b| b.is_ascii_hexdigit())
+            {
+                return Err(error(&format!("invalid {algorithm} checksum")));
+            }
+            let checksum = Checksum {
+                algorithm,
+                hash: hash.to_ascii_lowercase(),
+            };
+            entries.push((dir.join(name), checksum));
+        }
+        Ok(Self { entries })
+    }
+}
+
+/// Splits a checksum line into an algorithm tag, a file name and a checksum
+///
+/// Both `<checksum>  <name>` (with `*` instead of the second space in
+/// binary mode) and `<TAG> (<name>) = <checksum>` lines are accepted.
+/// A line starting with a backslash has `\\`, `\n` and `\r` escaped in the name.
+fn parse_line(line: &str) -> Option<(Option<&str>, String, &str)> {
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+    let (tag, name, hash) = match line.split_once(' ') {
+        Some((hash, rest)) if hash.bytes().all(|b| b.is_ascii_hexdigit()) => {
+            let name = rest.strip_prefix(' ').or_else(|| rest.strip_prefix('*'))?;
+            (None, name, hash)
+        }
+        _ => {
+            let (tag, rest) = line.split_once(" (")?;
+            let (name, hash) = rest.rsplit_once(") = ")?;
+            (Some(tag), name, hash)
+        }
+    };
+    if name.is_empty() {
+        return None;
+    }
+    let name = if escaped {
+        unescape(name)?
+    } else {
+        name.to_owned()
+    };
+    Some((tag, name, hash))
+}
+
+fn unescape(name: &str) -> Option<String> {
+    let mut result = String::with_capacity(name.len());
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next()? {
+            '\\' => result.push('\\'),
+            'n' => result.push('\n'),
+            'r' => result.push('\r'),
+            _ => return None,
+        }
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempdir::TempDir;
+
+    const SHA256: &str = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+    const MD5: &str = "b1946ac92492d2347c6235b4d2611184";
+
+    #[test]
+    fn test_manifest() {
+        let tmp = TempDir::new("test_manifest").unwrap();
+        let path = tmp.path().join("SUMS");
+        let content = format!(
+            "{SHA256}  dir/file\n{SHA256} *binary\n\\{SHA256}  new\\nline\n\
+             MD5 (a (1).txt) = {MD5}\n\n# comment\n{SHA256}  /absolute\n"
+        );
+        fs::write(&path, content).unwrap();
+
+        let manifest = Manifest::read(&path, Some(Algorithm::Sha256)).unwrap();
+        let sha256 = Checksum {
+            algorithm: Algorithm::Sha256,
+            hash: SHA256.to_owned(),
+        };
+        let md5 = Checksum {
+            algorithm: Algorithm::Md5,
+            hash: MD5.to_owned(),
+        };
+        assert_eq!(
+            manifest.entries,
+            [
+                (tmp.path().join("dir/file"), sha256.clone()),
+                (tmp.path().join("binary"), sha256.clone()),
+                (tmp.path().join("new\nline"), sha256.clone()),
+                (tmp.path().join("a (1).txt"), md5),
+                (PathBuf::from("/absolute"), sha256),
+            ]
+        );
+        assert!(Manifest::read(&path, None).is_err());
+
+        fs::write(&path, format!("{MD5}  file\n")).unwrap();
+        assert!(Manifest::read(&path, None).is_err());
+        let manifest = Manifest::read(&path, Some(Algorithm::Md5)).unwrap();
+        assert_eq!(manifest.entries[0].1.algorithm, Algorithm::Md5);
+        let manifest = Manifest::read(&path, Some(Algorithm::Xxh3)).unwrap();
+        assert_eq!(manifest.entries[0].1.algorithm, Algorithm::Xxh3);
+        assert!(Manifest::read(&path, Some(Algorithm::Blake3)).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+use crate::action::ActionOptions;
+use crate::escape;
+use crate::hash::Algorithm;
+use crate::state::FileState;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// A planned action on a duplicate file
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct PlanEntry {
+    pub target: FileState,
+    pub reference: FileState,
+    /// A path to the deduplicated directory containing the target
+    #[serde(with = "escape::path")]
+    pub root: PathBuf,
+}
+
+/// A list of actions to be reviewed and applied later
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Plan {
+    pub action: ActionOptions,
+    /// Hash algorithm of all hashes in the file
+    #[serde(default)]
+    pub algorithm: Algorithm,
+    pub entries: Vec<PlanEntry>,
+}
+
+impl Plan {
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writeln!(writer)?;
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::Action;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_plan() {
+        let tmp = TempDir::new("test_plan").unwrap();
+        let reference = tmp.path().join("reference");
+        let target = tmp.path().join("target");
+        let plan_path = tmp.path().join("plan.json");
+        fs::write(&reference, "content").unwrap();
+        fs::write(&target, "content").unwrap();
+
+        let hash = Algorithm::Xxh3
+            .hash_file(&target, None)
+            .unwrap()
+            .to_string();
+        let plan = Plan {
+            action: ActionOptions {
+                action: Action::Move,
+                relative: false,
+                quarantine: Some(tmp.path().join("quarantine")),
+            },
+            algorithm: Algorithm::Xxh3,
+            entries: vec![PlanEntry {
+                target: FileState {
+                    hash: Some(hash.clone()),
+                    ..FileState::capture(&target).unwrap()
+                },
+                reference: FileState {
+                    hash: Some(hash),
+                    ..FileState::capture(&reference).unwrap()
+                },
+                root: tmp.path().to_owned(),
+            }],
+        };
+        plan.write(&plan_path).unwrap();
+        let read = Plan::read(&plan_path).unwrap();
+        assert_eq!(read.action.quarantine, plan.action.quarantine);
+        assert_eq!(read.algorithm, Algorithm::Xxh3);
+        assert_eq!(read.entries, plan.entries);
+        assert_eq!(
+            read.entries[0].target.check(Some(read.algorithm)).unwrap(),
+            None
+        );
+
+        fs::write(&target, "CONTENT").unwrap();
+        assert!(read.entries[0]
+            .target
+            .check(Some(read.algorithm))
+            .unwrap()
+            .is_some());
+        fs::remove_file(&target).unwrap();
+        assert!(read.entries[0]
+            .target
+            .check(Some(read.algorithm))
+            .unwrap()
+            .is_some());
+    }
+}
@@ -0,0 +1,430 @@
+use crate::cache::{HashCache, Stamp};
+use crate::hash::{Algorithm, Digest, PARTIAL_HASH_SIZE};
+use crate::index::Index;
+use crate::manifest::Checksum;
+use crate::state::FileId;
+use crate::MatchMode;
+use rayon::prelude::*;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs::Metadata;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// A file with lazily computed content hashes
+///
+/// Each hash is computed at most once, so a file can be compared
+/// with any number of other files while being read at most twice.
+/// Threads needing a hash which is being computed wait for it.
+pub struct HashedFile {
+    path: PathBuf,
+    size: u64,
+    id: Option<FileId>,
+    algorithm: Algorithm,
+    partial_hash: Mutex<Option<Digest>>,
+    full_hash: Mutex<Option<Digest>>,
+    /// A persistent cache the hashes are taken from and stored to
+    cache: Option<(Arc<HashCache>, Stamp)>,
+}
+
+impl HashedFile {
+    pub fn new(path: PathBuf, meta: &Metadata, algorithm: Algorithm) -> Self {
+        Self {
+            path,
+            size: meta.len(),
+            id: FileId::of(meta),
+            algorithm,
+            partial_hash: Mutex::new(None),
+            full_hash: Mutex::new(None),
+            cache: None,
+        }
+    }
+
+    /// Creates a file whose hashes are taken from a persistent cache, if known
+    pub fn with_cache(
+        path: PathBuf,
+        meta: &Metadata,
+        algorithm: Algorithm,
+        cache: Arc<HashCache>,
+    ) -> Self {
+        let mut file = Self::new(path, meta, algorithm);
+        if let Some(stamp) = Stamp::of(meta) {
+            if let Some(hashes) = cache.lookup(&stamp, algorithm) {
+                file.partial_hash = Mutex::new(hashes.partial);
+                file.full_hash = Mutex::new(hashes.full);
+            }
+            file.cache = Some((cache, stamp));
+        }
+        file
+    }
+
+    /// Creates a file whose hashes are already known, so it is never read
+    pub fn indexed(
+        path: PathBuf,
+        size: u64,
+        id: Option<FileId>,
+        algorithm: Algorithm,
+        partial: Digest,
+        full: Digest,
+    ) -> Self {
+        Self {
+            path,
+            size,
+            id,
+            algorithm,
+            partial_hash: Mutex::new(Some(partial)),
+            full_hash: Mutex::new(Some(full)),
+            cache: None,
+        }
+    }
+
+    /// Returns a hash of the first `PARTIAL_HASH_SIZE` bytes of the file
+    pub fn partial_hash(&self) -> io::Result<Digest> {
+        cached_hash(&self.partial_hash, || {
+            let hash = self
+                .algorithm
+                .hash_file(&self.path, Some(PARTIAL_HASH_SIZE))?;
+            if let Some((cache, stamp)) = &self.cache {
+                cache.store(stamp, self.algorithm, Some(hash), None);
+            }
+            Ok(hash)
+        })
+    }
+
+    /// Returns a hash of the whole file
+    pub fn full_hash(&self) -> io::Result<Digest> {
+        if self.size <= PARTIAL_HASH_SIZE {
+            return self.partial_hash();
+        }
+        cached_hash(&self.full_hash, || {
+            let hash = self.algorithm.hash_file(&self.path, None)?;
+            if let Some((cache, stamp)) = &self.cache {
+                cache.store(stamp, self.algorithm, None, Some(hash));
+            }
+            Ok(hash)
+        })
+    }
+
+    /// Returns a hash of the whole file if it has already been computed
+    pub fn known_full_hash(&self) -> Option<Digest> {
+        if self.size <= PARTIAL_HASH_SIZE {
+            *self.partial_hash.lock().unwrap()
+        } else {
+            *self.full_hash.lock().unwrap()
+        }
+    }
+
+    /// Returns a checksum of the whole file as written in checksum manifests
+    pub fn checksum(&self, algorithm: Algorithm) -> io::Result<String> {
+        let hash = if algorithm == self.algorithm {
+            self.full_hash()?
+        } else {
+            algorithm.hash_file(&self.path, None)?
+        };
+        Ok(hash.to_string())
+    }
+
+    /// Checks whether two files of the same size have the same content
+    fn same_content(&self, other: &HashedFile) -> io::Result<bool> {
+        Ok(self.size == other.size
+            && self.partial_hash()? == other.partial_hash()?
+            && self.full_hash()? == other.full_hash()?)
+    }
+}
+
+/// Returns a cached hash, computing it first if needed
+fn cached_hash(
+    cache: &Mutex<Option<Digest>>,
+    compute: impl FnOnce() -> io::Result<Digest>,
+) -> io::Result<Digest> {
+    let mut cache = cache.lock().unwrap();
+    if let Some(hash) = *cache {
+        return Ok(hash);
+    }
+    let hash = compute()?;
+    *cache = Some(hash);
+    Ok(hash)
+}
+
+/// Hashed files by their identity
+///
+/// All hard links to a file share a single `HashedFile`, so the file
+/// is read at most twice regardless of the number of its links.
+#[derive(Default)]
+pub struct Inodes {
+    files: Mutex<HashMap<FileId, Arc<HashedFile>>>,
+    algorithm: Algorithm,
+    cache: Option<Arc<HashCache>>,
+}
+
+impl Inodes {
+    /// Creates an empty set of files hashed with `algorithm`, whose hashes
+    /// are kept in a persistent cache if given
+    pub fn new(algorithm: Algorithm, cache: Option<Arc<HashCache>>) -> Self {
+        Self {
+            files: Mutex::new(HashMap::new()),
+            algorithm,
+            cache,
+        }
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// Returns the hashed file at `path`, shared with all other links to it
+    pub fn get(&self, path: &Path, meta: &Metadata) -> Arc<HashedFile> {
+        let new = || {
+            let path = path.to_owned();
+            Arc::new(match &self.cache {
+                Some(cache) => HashedFile::with_cache(path, meta, self.algorithm, cache.clone()),
+                None => HashedFile::new(path, meta, self.algorithm),
+            })
+        };
+        match FileId::of(meta) {
+            Some(id) => self
+                .files
+                .lock()
+                .unwrap()
+                .entry(id)
+                .or_insert_with(new)
+                .clone(),
+            None => new(),
+        }
+    }
+}
+
+/// A path to a file together with the file's hashes, which are shared by all its links
+pub type Link = (PathBuf, Arc<HashedFile>);
+
+/// A reference file matching a target file
+#[derive(Debug, PartialEq, Eq)]
+pub enum Match<'a> {
+    /// The target file is a copy of the reference file
+    Duplicate(&'a Path),
+    /// The target file is a hard link to the reference file
+    Linked(&'a Path),
+}
+
+/// Pairs of matching files
+#[derive(Debug, Default)]
+pub struct Matches {
+    /// `(duplicate, reference)` pairs of distinct files with the same content
+    pub duplicates: Vec<(PathBuf, PathBuf)>,
+    /// `(link, reference)` pairs of paths to the same file
+    pub linked: Vec<(PathBuf, PathBuf)>,
+    /// Full hashes of duplicates computed while they were matched, by their paths
+    pub hashes: HashMap<PathBuf, Digest>,
+}
+
+/// Reference files indexed by size
+pub struct ReferenceData {
+    mode: MatchMode,
+    files: HashMap<u64, Vec<Link>>,
+    /// The first path of each reference file
+    paths: HashMap<FileId, PathBuf>,
+    /// Paths to reference files known only by their checksums
+    checksums: BTreeMap<Algorithm, HashMap<String, Vec<PathBuf>>>,
+    /// Names of the files known only by their checksums
+    checksum_names: HashSet<OsString>,
+    inodes: Inodes,
+}
+
+impl ReferenceData {
+    /// Indexes reference files
+    ///
+    /// # Arguments
+    /// * `paths` - Paths to the reference files
+    /// * `mode` - How reference candidates are selected for a target file
+    /// * `inodes` - Hashed files, shared by reference and target files
+    pub fn new(paths: Vec<PathBuf>, mode: MatchMode, inodes: Inodes) -> io::Result<Self> {
+        let mut data = Self {
+            mode,
+            files: HashMap::new(),
+            paths: HashMap::new(),
+            checksums: BTreeMap::new(),
+            checksum_names: HashSet::new(),
+            inodes,
+        };
+        let metadata = paths
+            .par_iter()
+            .map(|path| path.metadata())
+            .collect::<io::Result<Vec<_>>>()?;
+        for (path, meta) in paths.into_iter().zip(metadata) {
+            let file = data.inodes.get(&path, &meta);
+            if let Some(id) = file.id {
+                data.paths.entry(id).or_insert_with(|| path.clone());
+            }
+            let entry = data.files.entry(meta.len()).or_default();
+            entry.push((path, file));
+        }
+        Ok(data)
+    }
+
+    /// Adds reference files from an index, using their saved hashes
+    ///
+    /// The index must have been built with the same hash algorithm as the files
+    /// it is compared with.
+    pub fn add_index(&mut self, index: Index) -> io::Result<()> {
+        for entry in index.entries {
+            let (partial, full) = entry.hashes()?;
+            let state = entry.state;
+            let file = HashedFile::indexed(
+                state.path.clone(),
+                state.size,
+                state.id,
+                index.algorithm,
+                partial,
+                full,
+            );
+            if let Some(id) = state.id {
+                self.paths.entry(id).or_insert_with(|| state.path.clone());
+            }
+            let entry = self.files.entry(state.size).or_default();
+            entry.push((state.path, Arc::new(file)));
+        }
+        Ok(())
+    }
+
+    /// Adds a reference file which cannot be read, but whose checksum is known
+    pub fn add_checksum(&mut self, path: PathBuf, checksum: Checksum) {
+        self.checksum_names
+            .extend(path.file_name().map(ToOwned::to_owned));
+        let by_hash = self.checksums.entry(checksum.algorithm).or_default();
+        by_hash.entry(checksum.hash).or_default().push(path);
+    }
+
+    /// Returns a hash of the whole `file` if it was computed while finding its duplicate
+    pub fn known_hash(&self, file: &Path) -> io::Result<Option<Digest>> {
+        Ok(self.inodes.get(file, &file.metadata()?).known_full_hash())
+    }
+
+    /// Returns a reference file with the same content as `file`
+    ///
+    /// If `file` is a hard link to a reference file, it is returned as
+    /// `Match::Linked`, since a file is never a duplicate of itself.
+    /// Otherwise in `MatchMode::Name` only reference files with the same
+    /// name are considered. Reference files known only by their checksums
+    /// are considered last, hashing `file` with each of their algorithms.
+    pub fn find_duplicate(&self, file: impl AsRef<Path>) -> io::Result<Option<Match<'_>>> {
+        let file = file.as_ref();
+        let meta = file.metadata()?;
+        let target = self.inodes.get(file, &meta);
+        if let Some(path) = target.id.and_then(|id| self.paths.get(&id)) {
+            return Ok(Some(Match::Linked(path)));
+        }
+        let same_name =
+            |path: &Path| self.mode == MatchMode::Content || path.file_name() == file.file_name();
+        let candidates = self.files.get(&meta.len()).map_or(&[][..], Vec::as_slice);
+        for (path, candidate) in candidates {
+            if same_name(path) && candidate.same_content(&target)? {
+                return Ok(Some(Match::Duplicate(path)));
+            }
+        }
+        if self.mode == MatchMode::Name
+            && !file
+                .file_name()
+                .is_some_and(|name| self.checksum_names.contains(name))
+        {
+            return Ok(None);
+        }
+        for (algorithm, by_hash) in &self.checksums {
+            let Some(paths) = by_hash.get(&target.checksum(*algorithm)?) else {
+                continue;
+            };
+            if let Some(path) = paths.iter().find(|path| same_name(path)) {
+                return Ok(Some(Match::Duplicate(path)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_same_content() {
+        let tmp = TempDir::new("test_same_content").unwrap();
+        let tmp_path = tmp.path();
+
+        let mut data = vec![0x55; 3 * PARTIAL_HASH_SIZE as usize];
+        fs::write(tmp_path.join("file1"), &data).unwrap();
+        fs::write(tmp_path.join("file2"), &data).unwrap();
+        *data.last_mut().unwrap() = 0xaa;
+        fs::write(tmp_path.join("file3"), &data).unwrap();
+
+        let file = |name| {
+            let path = tmp_path.join(name);
+            let meta = path.metadata().unwrap();
+            HashedFile::new(path, &meta, Algorithm::Blake3)
+        };
+        let file1 = file("file1");
+        let file2 = file("file2");
+        let file3 = file("file3");
+        assert!(file1.same_content(&file2).unwrap());
+        assert!(!file1.same_content(&file3).unwrap());
+        assert_eq!(file1.partial_hash().unwrap(), file3.partial_hash().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hard_links() {
+        let tmp = TempDir::new("test_hard_links").unwrap();
+        let reference = tmp.path().join("ref");
+        let target = tmp.path().join("target");
+        fs::create_dir(&reference).unwrap();
+        fs::create_dir(&target).unwrap();
+        fs::write(reference.join("file"), "content").unwrap();
+        fs::hard_link(reference.join("file"), reference.join("alias")).unwrap();
+        fs::hard_link(reference.join("file"), target.join("link")).unwrap();
+        fs::write(target.join("copy"), "content").unwrap();
+
+        let data = ReferenceData::new(
+            vec![reference.join("file"), reference.join("alias")],
+            MatchMode::Content,
+            Inodes::default(),
+        )
+        .unwrap();
+        let candidates = &data.files[&7];
+        assert!(Arc::ptr_eq(&candidates[0].1, &candidates[1].1));
+        assert_eq!(
+            data.find_duplicate(target.join("link")).unwrap(),
+            Some(Match::Linked(&reference.join("file")))
+        );
+        assert_eq!(
+            data.find_duplicate(target.join("copy")).unwrap(),
+            Some(Match::Duplicate(&reference.join("file")))
+        );
+    }
+
+    #[test]
+    fn test_checksums() {
+        let tmp = TempDir::new("test_checksums").unwrap();
+        let target = tmp.path().join("photo.jpg");
+        fs::write(&target, "content").unwrap();
+        let checksum = Checksum {
+            algorithm: Algorithm::Sha256,
+            hash: Algorithm::Sha256
+                .hash_file(&target, None)
+                .unwrap()
+                .to_string(),
+        };
+        let offsite = Path::new("/offsite/photo.jpg");
+
+        let mut data = ReferenceData::new(vec![], MatchMode::Name, Inodes::default()).unwrap();
+        data.add_checksum(offsite.to_owned(), checksum.clone());
+        assert_eq!(
+            data.find_duplicate(&target).unwrap(),
+            Some(Match::Duplicate(offsite))
+        );
+
+        let mut data = ReferenceData::new(vec![], MatchMode::Name, Inodes::default()).unwrap();
+        data.add_checksum(offsite.with_file_name("other.jpg"), checksum);
+        assert_eq!(data.find_duplicate(&target).unwrap(), None);
+    }
+}
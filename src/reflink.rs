@@ -0,0 +1,124 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+/// Makes `target` share its storage with an identical `reference` file
+///
+/// The kernel verifies that the contents of both files are the same before
+/// sharing them, so a file which was modified in the meantime is never lost.
+/// Only supported on Linux filesystems with reflink support, such as btrfs and XFS.
+#[cfg(target_os = "linux")]
+pub fn reflink(target: &Path, reference: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    /// `_IOWR(0x94, 54, struct file_dedupe_range)` from `linux/fs.h`
+    const FIDEDUPERANGE: u32 = 0xc018_9436;
+    const FILE_DEDUPE_RANGE_DIFFERS: i32 = 1;
+    /// Some filesystems limit the length of a single deduplication request
+    const MAX_CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+
+    #[repr(C)]
+    struct FileDedupeRange {
+        src_offset: u64,
+        src_length: u64,
+        dest_count: u16,
+        reserved1: u16,
+        reserved2: u32,
+        dest_fd: i64,
+        dest_offset: u64,
+        bytes_deduped: u64,
+        status: i32,
+        reserved: u32,
+    }
+
+    let src = File::open(reference)?;
+    let dest = File::options().write(true).open(target)?;
+    let len = src.metadata()?.len();
+    if dest.metadata()?.len() != len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "file sizes differ",
+        ));
+    }
+
+    let mut offset = 0;
+    while offset < len {
+        let mut range = FileDedupeRange {
+            src_offset: offset,
+            src_length: (len - offset).min(MAX_CHUNK_SIZE),
+            dest_count: 1,
+            reserved1: 0,
+            reserved2: 0,
+            dest_fd: dest.as_raw_fd().into(),
+            dest_offset: offset,
+            bytes_deduped: 0,
+            status: 0,
+            reserved: 0,
+        };
+        let ret = unsafe { libc::ioctl(src.as_raw_fd(), FIDEDUPERANGE as _, &mut range) };
+        if ret < 0 {
+            return Err(map_unsupported(io::Error::last_os_error()));
+        }
+        if range.status < 0 {
+            return Err(map_unsupported(io::Error::from_raw_os_error(-range.status)));
+        }
+        if range.status == FILE_DEDUPE_RANGE_DIFFERS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "file contents differ",
+            ));
+        }
+        if range.bytes_deduped == 0 {
+            return Err(io::Error::other("no data was deduplicated"));
+        }
+        offset += range.bytes_deduped;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn map_unsupported(e: io::Error) -> io::Error {
+    match e.raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY) | Some(libc::EINVAL) => io::Error::new(
+            io::ErrorKind::Unsupported,
+            "filesystem does not support reflinks",
+        ),
+        _ => e,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn reflink(_target: &Path, _reference: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reflinks are only supported on Linux",
+    ))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempdir::TempDir;
+
+    /// Requires a directory on a filesystem with reflink support in `DEDUP_REFLINK_DIR`,
+    /// e.g. a loop-mounted image created with `mkfs.xfs -m reflink=1` or `mkfs.btrfs`.
+    #[test]
+    #[ignore]
+    fn test_reflink() {
+        let dir = std::env::var("DEDUP_REFLINK_DIR").expect("DEDUP_REFLINK_DIR is not set");
+        let tmp = TempDir::new_in(dir, "test_reflink").unwrap();
+        let reference = tmp.path().join("reference");
+        let target = tmp.path().join("target");
+        let data: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+        fs::write(&reference, &data).unwrap();
+        fs::write(&target, &data).unwrap();
+
+        reflink(&target, &reference).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), data);
+
+        fs::write(&target, "modified").unwrap();
+        let e = reflink(&target, &reference).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}
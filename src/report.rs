@@ -0,0 +1,274 @@
+use crate::action::Action;
+use crate::escape;
+use crate::hash::Algorithm;
+use crate::table::TableWriter;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// Output format of the duplicate report
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// Human-readable text
+    Text,
+    /// A single JSON document
+    Json,
+    /// One JSON object per line
+    Ndjson,
+}
+
+/// Result of handling a duplicate file
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    /// No action was taken because of `--dry-run`
+    DryRun,
+    /// The action was written to a shell script instead of being applied
+    Scripted,
+    /// The action was applied
+    Done,
+    /// The action was not applied, but processing continued
+    Skipped,
+    /// The action failed and processing was stopped
+    Failed,
+    /// The file is already a hard link to the reference file, so no action was needed
+    Linked,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Outcome::DryRun => "dry-run",
+            Outcome::Scripted => "scripted",
+            Outcome::Done => "done",
+            Outcome::Skipped => "skipped",
+            Outcome::Failed => "failed",
+            Outcome::Linked => "linked",
+        })
+    }
+}
+
+/// A report entry for a single duplicate file
+#[derive(Debug, Serialize)]
+pub struct Record {
+    #[serde(with = "escape::path")]
+    pub target: PathBuf,
+    #[serde(with = "escape::path")]
+    pub reference: PathBuf,
+    pub size: u64,
+    /// Modification time of the duplicate before the action
+    #[serde(skip)]
+    pub target_modified: Option<SystemTime>,
+    #[serde(skip)]
+    pub reference_modified: Option<SystemTime>,
+    /// The algorithm of `hash`, so that reports of different runs can be compared
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub algorithm: Option<Algorithm>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    pub action: Action,
+    pub outcome: Outcome,
+    /// Why the action was skipped or failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl Record {
+    /// Creates a record with the current size and modification times of both files
+    pub fn new(target: PathBuf, reference: PathBuf, action: Action, outcome: Outcome) -> Self {
+        let target_meta = target.metadata().ok();
+        Self {
+            size: target_meta.as_ref().map_or(0, |meta| meta.len()),
+            target_modified: target_meta.and_then(|meta| meta.modified().ok()),
+            reference_modified: reference.metadata().and_then(|meta| meta.modified()).ok(),
+            target,
+            reference,
+            algorithm: None,
+            hash: None,
+            action,
+            outcome,
+            message: None,
+        }
+    }
+}
+
+/// Totals over all reported duplicates
+#[derive(Debug, Default, Serialize)]
+pub struct Summary {
+    pub duplicates: usize,
+    /// Total size of all duplicates
+    pub bytes: u64,
+    pub done: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    /// Number of files which are already hard links to their reference files
+    pub linked: usize,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Line<'a> {
+    Duplicate(&'a Record),
+    Linked(&'a Record),
+    Summary(&'a Summary),
+}
+
+#[derive(Serialize)]
+struct Document<'a> {
+    duplicates: &'a [Record],
+    linked: &'a [Record],
+    summary: &'a Summary,
+}
+
+/// Writes progress messages and the duplicate report to the standard output
+///
+/// In machine-readable formats progress messages are written to the standard
+/// error, so that the standard output contains nothing but the report.
+/// Records can additionally be exported to a CSV or TSV table.
+pub struct Reporter {
+    format: Format,
+    table: Option<TableWriter<BufWriter<File>>>,
+    records: Vec<Record>,
+    linked: Vec<Record>,
+    summary: Summary,
+}
+
+impl Reporter {
+    pub fn new(format: Format, table: Option<TableWriter<BufWriter<File>>>) -> Self {
+        Self {
+            format,
+            table,
+            records: Vec::new(),
+            linked: Vec::new(),
+            summary: Summary::default(),
+        }
+    }
+
+    /// Whether records need content hashes
+    pub fn needs_hashes(&self) -> bool {
+        self.format != Format::Text
+    }
+
+    pub fn progress(&self, message: &str) {
+        match self.format {
+            Format::Text => println!("{message}"),
+            Format::Json | Format::Ndjson => eprintln!("{message}"),
+        }
+    }
+
+    /// Reports that a duplicate was found, before any action is applied to it
+    pub fn found(&self, record: &Record) {
+        if self.format == Format::Text {
+            println!(
+                "Duplicate found: {:?} -> {:?}",
+                record.target, record.reference
+            );
+        }
+    }
+
+    /// Reports the outcome of handling a duplicate
+    ///
+    /// Records with `Outcome::Linked` are reported separately from duplicates.
+    pub fn record(&mut self, record: Record) -> io::Result<()> {
+        if record.outcome != Outcome::Linked {
+            self.summary.duplicates += 1;
+            self.summary.bytes += record.size;
+        }
+        match record.outcome {
+            Outcome::DryRun | Outcome::Scripted => {}
+            Outcome::Done => self.summary.done += 1,
+            Outcome::Skipped => self.summary.skipped += 1,
+            Outcome::Failed => self.summary.failed += 1,
+            Outcome::Linked => self.summary.linked += 1,
+        }
+        if let Some(table) = &mut self.table {
+            table.write(&record)?;
+        }
+        if record.outcome == Outcome::Linked {
+            return match self.format {
+                Format::Text => {
+                    println!(
+                        "Already linked: {:?} -> {:?}",
+                        record.target, record.reference
+                    );
+                    Ok(())
+                }
+                Format::Json => {
+                    self.linked.push(record);
+                    Ok(())
+                }
+                Format::Ndjson => write_line(&Line::Linked(&record)),
+            };
+        }
+        match self.format {
+            Format::Text => {
+                if let (Outcome::Skipped, Some(message)) = (record.outcome, &record.message) {
+                    eprintln!("Skipped {:?}: {message}", record.target);
+                }
+            }
+            Format::Json => self.records.push(record),
+            Format::Ndjson => write_line(&Line::Duplicate(&record))?,
+        }
+        Ok(())
+    }
+
+    /// Writes the summary, and the whole report in the JSON format
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(table) = &mut self.table {
+            table.flush()?;
+        }
+        match self.format {
+            Format::Text => Ok(()),
+            Format::Json => write_line(&Document {
+                duplicates: &self.records,
+                linked: &self.linked,
+                summary: &self.summary,
+            }),
+            Format::Ndjson => write_line(&Line::Summary(&self.summary)),
+        }
+    }
+}
+
+fn write_line(value: &impl Serialize) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    serde_json::to_writer(&mut stdout, value)?;
+    writeln!(stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ndjson_line() {
+        let record = Record {
+            target: PathBuf::from("target/file"),
+            reference: PathBuf::from("ref/file"),
+            size: 7,
+            target_modified: None,
+            reference_modified: None,
+            algorithm: Some(Algorithm::Sha256),
+            hash: Some("abcd".to_owned()),
+            action: Action::Delete,
+            outcome: Outcome::DryRun,
+            message: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&Line::Duplicate(&record)).unwrap(),
+            r#"{"type":"duplicate","target":"target/file","reference":"ref/file","size":7,"algorithm":"sha256","hash":"abcd","action":"delete","outcome":"dry-run"}"#
+        );
+        let summary = Summary {
+            duplicates: 1,
+            bytes: 7,
+            ..Summary::default()
+        };
+        assert_eq!(
+            serde_json::to_string(&Line::Summary(&summary)).unwrap(),
+            r#"{"type":"summary","duplicates":1,"bytes":7,"done":0,"skipped":0,"failed":0,"linked":0}"#
+        );
+    }
+}
@@ -0,0 +1,103 @@
+use crate::state::FileId;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A directory given on the command line
+struct Root<'a> {
+    path: &'a Path,
+    canonical: PathBuf,
+    /// Identities of the directory and all its ancestors
+    ids: Vec<FileId>,
+}
+
+impl<'a> Root<'a> {
+    fn new(path: &'a Path) -> io::Result<Self> {
+        let canonical = path.canonicalize()?;
+        let mut ids = Vec::new();
+        for ancestor in canonical.ancestors() {
+            ids.extend(FileId::of(&ancestor.metadata()?));
+        }
+        Ok(Self {
+            path,
+            canonical,
+            ids,
+        })
+    }
+
+    /// Checks whether one of the directories contains the other
+    ///
+    /// Besides comparing canonical paths, the device and inode numbers of the
+    /// directories are compared with those of the ancestors of the other one,
+    /// which detects the same tree mounted at several places.
+    fn overlaps(&self, other: &Root) -> bool {
+        self.canonical.starts_with(&other.canonical)
+            || other.canonical.starts_with(&self.canonical)
+            || self.ids.first().is_some_and(|id| other.ids.contains(id))
+            || other.ids.first().is_some_and(|id| self.ids.contains(id))
+    }
+}
+
+fn overlap_error(kind: &str, a: &Root, other_kind: &str, b: &Root) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "{kind} directory {:?} and {other_kind} directory {:?} overlap",
+            a.path, b.path
+        ),
+    )
+}
+
+/// Refuses reference and target directories which overlap
+///
+/// A target directory must not contain or be contained in a reference
+/// directory or another target directory, since a file could then be found
+/// as a duplicate of itself.
+pub fn check_roots(references: &[PathBuf], targets: &[PathBuf]) -> io::Result<()> {
+    let references = references
+        .iter()
+        .map(|path| Root::new(path))
+        .collect::<io::Result<Vec<_>>>()?;
+    let targets = targets
+        .iter()
+        .map(|path| Root::new(path))
+        .collect::<io::Result<Vec<_>>>()?;
+    for (i, target) in targets.iter().enumerate() {
+        if let Some(reference) = references.iter().find(|root| root.overlaps(target)) {
+            return Err(overlap_error("reference", reference, "target", target));
+        }
+        if let Some(other) = targets[..i].iter().find(|root| root.overlaps(target)) {
+            return Err(overlap_error("target", other, "target", target));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_check_roots() {
+        let tmp = TempDir::new("test_check_roots").unwrap();
+        fs::create_dir_all(tmp.path().join("ref").join("sub")).unwrap();
+        fs::create_dir(tmp.path().join("target")).unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(tmp.path().join("ref"), tmp.path().join("link")).unwrap();
+
+        let paths = |names: &[&str]| -> Vec<PathBuf> {
+            names.iter().map(|name| tmp.path().join(name)).collect()
+        };
+        let check = |references: &[&str], targets: &[&str]| {
+            check_roots(&paths(references), &paths(targets)).is_ok()
+        };
+        assert!(check(&["ref"], &["target"]));
+        assert!(!check(&["ref"], &["ref"]));
+        assert!(!check(&["ref"], &["ref/sub"]));
+        assert!(!check(&["ref/sub"], &["ref"]));
+        assert!(!check(&[], &["target", "target/."]));
+        #[cfg(unix)]
+        assert!(!check(&["link"], &["ref/sub"]));
+    }
+}
@@ -0,0 +1,228 @@
+use crate::action::{temporary_path, unused_path, Action, ActionOptions};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Writes actions as a POSIX shell script instead of applying them
+pub struct ScriptWriter<W: Write> {
+    writer: W,
+    /// Paths the script creates, which later commands must not reuse
+    reserved: HashSet<PathBuf>,
+}
+
+impl ScriptWriter<BufWriter<File>> {
+    /// Creates an executable script file, unless `action` cannot be scripted
+    pub fn create(path: impl AsRef<Path>, action: Action) -> io::Result<Self> {
+        check_scriptable(action)?;
+        let file = File::create(path)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(std::fs::Permissions::from_mode(0o755))?;
+        }
+        Self::new(BufWriter::new(file))
+    }
+}
+
+impl<W: Write> ScriptWriter<W> {
+    /// Creates a writer and writes the script header
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writeln!(writer, "#!/bin/sh")?;
+        writeln!(writer, "# Generated by dedup")?;
+        writeln!(writer, "set -eu")?;
+        Ok(Self {
+            writer,
+            reserved: HashSet::new(),
+        })
+    }
+
+    /// Writes commands applying an action to a duplicate of a reference file
+    ///
+    /// Paths are written as absolute, so the script can be run from any directory.
+    ///
+    /// # Arguments
+    /// * `action` - The action to be applied
+    /// * `target` - A path to the duplicate file
+    /// * `reference` - A path to the reference file
+    /// * `root` - A path to the deduplicated directory containing `target`
+    pub fn write(
+        &mut self,
+        action: &ActionOptions,
+        target: &Path,
+        reference: &Path,
+        root: &Path,
+    ) -> io::Result<()> {
+        check_scriptable(action.action)?;
+        let target = &std::path::absolute(target)?;
+        let reference = &std::path::absolute(reference)?;
+        let root = &std::path::absolute(root)?;
+        let is_taken =
+            |path: &Path| path.exists() || path.is_symlink() || self.reserved.contains(path);
+        match action.action {
+            Action::Delete => self.command(&["rm", "--"], &[target]),
+            Action::Hardlink => {
+                let tmp = unused_path(&temporary_path(target), is_taken);
+                self.command(&["ln", "--"], &[reference, &tmp])?;
+                self.command(&["mv", "-f", "--"], &[&tmp, target])
+            }
+            Action::Symlink => {
+                let link = action.symlink_target(target, reference)?;
+                let tmp = unused_path(&temporary_path(target), is_taken);
+                self.command(&["ln", "-s", "--"], &[&link, &tmp])?;
+                self.command(&["mv", "-f", "--"], &[&tmp, target])
+            }
+            Action::Move => {
+                let destination = std::path::absolute(action.move_destination(target, root))?;
+                let destination = unused_path(&destination, is_taken);
+                self.command(&["mkdir", "-p", "--"], &[destination.parent().unwrap()])?;
+                self.command(&["mv", "--"], &[target, &destination])?;
+                self.reserved.insert(destination);
+                Ok(())
+            }
+            Action::Reflink | Action::Trash => unreachable!(),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    fn command(&mut self, words: &[&str], paths: &[&Path]) -> io::Result<()> {
+        self.writer.write_all(words.join(" ").as_bytes())?;
+        for path in paths {
+            self.writer.write_all(b" ")?;
+            self.writer.write_all(&quote(path.as_os_str()))?;
+        }
+        self.writer.write_all(b"\n")
+    }
+}
+
+/// Fails if an action cannot be written as shell commands
+pub fn check_scriptable(action: Action) -> io::Result<()> {
+    match action {
+        Action::Reflink | Action::Trash => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("the {action} action cannot be written as a shell script"),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Quotes an arbitrary string for the shell
+///
+/// The string is enclosed in single quotes, within which no character is special
+/// except the single quote itself, which is written as `'\''`.
+fn quote(s: &OsStr) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+    for &byte in os_str_bytes(s).iter() {
+        if byte == b'\'' {
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(byte);
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+#[cfg(unix)]
+fn os_str_bytes(s: &OsStr) -> Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    Cow::Borrowed(s.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn os_str_bytes(s: &OsStr) -> Cow<'_, [u8]> {
+    match s.to_string_lossy() {
+        Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
+        Cow::Owned(s) => Cow::Owned(s.into_bytes()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote(OsStr::new("a b")), b"'a b'");
+        assert_eq!(quote(OsStr::new("it's")), b"'it'\\''s'");
+    }
+
+    #[test]
+    fn test_absolute_paths() {
+        let mut script = ScriptWriter::new(Vec::new()).unwrap();
+        let action = ActionOptions {
+            action: Action::Delete,
+            relative: false,
+            quarantine: None,
+        };
+        let target = Path::new("target").join("file");
+        script
+            .write(
+                &action,
+                &target,
+                Path::new("reference"),
+                Path::new("target"),
+            )
+            .unwrap();
+        let absolute = std::env::current_dir().unwrap().join(&target);
+        let command = [b"rm -- ".as_slice(), &quote(absolute.as_os_str()), b"\n"].concat();
+        assert!(script.writer.ends_with(&command));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_script() {
+        use std::os::unix::ffi::OsStrExt;
+        use std::process::Command;
+        use tempdir::TempDir;
+
+        let tmp = TempDir::new("test_script").unwrap();
+        let reference = tmp.path().join("reference");
+        let root = tmp.path().join("target");
+        let target1 = root.join(OsStr::from_bytes(b"-it's\n$HOME \xff"));
+        let target2 = root.join("file2");
+        let quarantine = tmp.path().join("quarantine");
+        std::fs::create_dir(&root).unwrap();
+        for path in [&reference, &target1, &target2] {
+            std::fs::write(path, "content").unwrap();
+        }
+
+        let script_path = tmp.path().join("script.sh");
+        assert!(ScriptWriter::create(&script_path, Action::Trash).is_err());
+        assert!(!script_path.exists());
+        let mut script = ScriptWriter::create(&script_path, Action::Hardlink).unwrap();
+        let mut action = ActionOptions {
+            action: Action::Hardlink,
+            relative: false,
+            quarantine: Some(quarantine.clone()),
+        };
+        script.write(&action, &target1, &reference, &root).unwrap();
+        action.action = Action::Move;
+        script.write(&action, &target2, &reference, &root).unwrap();
+        action.action = Action::Trash;
+        assert!(script.write(&action, &target2, &reference, &root).is_err());
+        script.flush().unwrap();
+        drop(script);
+
+        let status = Command::new(&script_path)
+            .current_dir("/")
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert!(!target2.exists());
+        assert!(quarantine.join("file2").exists());
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 1);
+        {
+            use std::os::unix::fs::MetadataExt;
+            assert_eq!(
+                target1.metadata().unwrap().ino(),
+                reference.metadata().unwrap().ino()
+            );
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use crate::escape;
+use crate::hash::Algorithm;
+use serde::{Deserialize, Serialize};
+use std::fs::Metadata;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Identity of a file independent of its path
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileId {
+    pub device: u64,
+    pub inode: u64,
+}
+
+impl FileId {
+    /// Returns the device and inode numbers of a file, where the platform provides them
+    #[cfg(unix)]
+    pub fn of(meta: &Metadata) -> Option<Self> {
+        use std::os::unix::fs::MetadataExt;
+        Some(Self {
+            device: meta.dev(),
+            inode: meta.ino(),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn of(_meta: &Metadata) -> Option<Self> {
+        None
+    }
+}
+
+/// State of a file at the time it was compared
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileState {
+    #[serde(with = "escape::path")]
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: SystemTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<FileId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+impl FileState {
+    /// Captures the current metadata of a file
+    ///
+    /// The path is recorded as absolute, so the state does not depend on the current directory.
+    pub fn capture(path: &Path) -> io::Result<Self> {
+        let meta = path.symlink_metadata()?;
+        Ok(Self {
+            path: std::path::absolute(path)?,
+            size: meta.len(),
+            modified: meta.modified()?,
+            id: FileId::of(&meta),
+            hash: None,
+        })
+    }
+
+    /// Checks whether the file still has the captured state
+    ///
+    /// # Arguments
+    /// * `content` - The algorithm of the captured hash, if the file is to be
+    ///   hashed and compared with it
+    ///
+    /// # Returns
+    /// * `Ok(None)` if the file is unchanged
+    /// * `Ok(Some(reason))` if the file has changed
+    /// * `Err` if the check failed
+    pub fn check(&self, content: Option<Algorithm>) -> io::Result<Option<String>> {
+        let meta = match self.path.symlink_metadata() {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(Some(format!("{:?} no longer exists", self.path)));
+            }
+            Err(e) => return Err(e),
+        };
+        if !meta.is_file() || self.id.is_some() && FileId::of(&meta) != self.id {
+            return Ok(Some(format!("{:?} has been replaced", self.path)));
+        }
+        if meta.len() != self.size {
+            return Ok(Some(format!("size of {:?} has changed", self.path)));
+        }
+        if meta.modified()? != self.modified {
+            return Ok(Some(format!("{:?} has been modified", self.path)));
+        }
+        if let (Some(algorithm), Some(hash)) = (content, &self.hash) {
+            if algorithm.hash_file(&self.path, None)?.to_string() != *hash {
+                return Ok(Some(format!("content of {:?} has changed", self.path)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_check() {
+        let tmp = TempDir::new("test_check").unwrap();
+        let path = tmp.path().join("file");
+        fs::write(&path, "content").unwrap();
+        let state = FileState::capture(&path).unwrap();
+        assert_eq!(state.check(Some(Algorithm::Blake3)).unwrap(), None);
+
+        // Replace the file with another one of the same size and modification time
+        let other = tmp.path().join("other");
+        fs::write(&other, "CONTENT").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&other)
+            .unwrap()
+            .set_modified(state.modified)
+            .unwrap();
+        fs::rename(&other, &path).unwrap();
+        let reason = state.check(None).unwrap();
+        if cfg!(unix) {
+            assert_eq!(reason, Some(format!("{:?} has been replaced", state.path)));
+        }
+
+        let state = FileState {
+            hash: Some(
+                Algorithm::Blake3
+                    .hash_file(&path, None)
+                    .unwrap()
+                    .to_string(),
+            ),
+            ..FileState::capture(&path).unwrap()
+        };
+        fs::write(&path, "changed").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(state.modified)
+            .unwrap();
+        assert_eq!(state.check(None).unwrap(), None);
+        assert!(state.check(Some(Algorithm::Blake3)).unwrap().is_some());
+    }
+}
@@ -0,0 +1,162 @@
+use crate::escape::escape;
+use crate::report::Record;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const HEADER: [&str; 7] = [
+    "target",
+    "reference",
+    "size",
+    "target_mtime",
+    "reference_mtime",
+    "action",
+    "status",
+];
+
+/// Field separator of a table
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Separator {
+    Comma,
+    Tab,
+}
+
+/// Writes duplicate records as CSV or TSV
+///
+/// Paths are escaped with `escape::escape`. In CSV fields are quoted when
+/// needed; in TSV tabs and line breaks are additionally escaped as `\t`, `\n`
+/// and `\r`, so every record takes exactly one line.
+pub struct TableWriter<W: Write> {
+    writer: W,
+    separator: Separator,
+}
+
+impl TableWriter<BufWriter<File>> {
+    pub fn create(path: impl AsRef<Path>, separator: Separator) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), separator)
+    }
+}
+
+impl<W: Write> TableWriter<W> {
+    /// Creates a writer and writes the header
+    pub fn new(writer: W, separator: Separator) -> io::Result<Self> {
+        let mut table = Self { writer, separator };
+        table.write_row(&HEADER.map(String::from))?;
+        Ok(table)
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        let time = |time: Option<SystemTime>| time.map(format_time).unwrap_or_default();
+        self.write_row(&[
+            escape(record.target.as_os_str()),
+            escape(record.reference.as_os_str()),
+            record.size.to_string(),
+            time(record.target_modified),
+            time(record.reference_modified),
+            record.action.to_string(),
+            record.outcome.to_string(),
+        ])
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    fn write_row(&mut self, fields: &[String]) -> io::Result<()> {
+        let fields: Vec<_> = fields.iter().map(|field| self.quote(field)).collect();
+        match self.separator {
+            Separator::Comma => write!(self.writer, "{}\r\n", fields.join(",")),
+            Separator::Tab => writeln!(self.writer, "{}", fields.join("\t")),
+        }
+    }
+
+    fn quote(&self, field: &str) -> String {
+        match self.separator {
+            Separator::Comma if field.contains([',', '"', '\r', '\n']) => {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            }
+            Separator::Comma => field.to_owned(),
+            Separator::Tab => field
+                .replace('\t', "\\t")
+                .replace('\n', "\\n")
+                .replace('\r', "\\r"),
+        }
+    }
+}
+
+/// Formats a time as `YYYY-MM-DDThh:mm:ssZ` in UTC
+pub fn format_time(time: SystemTime) -> String {
+    let secs = match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs_f64().ceil() as i64),
+    };
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    // Converts days since 1970-01-01 to a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::Action;
+    use crate::report::Outcome;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        let time = UNIX_EPOCH + Duration::from_secs(1_689_424_496);
+        assert_eq!(format_time(time), "2023-07-15T12:34:56Z");
+        let time = UNIX_EPOCH + Duration::from_secs(951_825_600);
+        assert_eq!(format_time(time), "2000-02-29T12:00:00Z");
+    }
+
+    #[test]
+    fn test_table_writer() {
+        let record = Record {
+            target: PathBuf::from("t/a,\"b\"\tc"),
+            reference: PathBuf::from("r\\a"),
+            size: 7,
+            target_modified: Some(UNIX_EPOCH),
+            reference_modified: None,
+            algorithm: None,
+            hash: None,
+            action: Action::Hardlink,
+            outcome: Outcome::Done,
+            message: None,
+        };
+
+        let mut table = TableWriter::new(Vec::new(), Separator::Comma).unwrap();
+        table.write(&record).unwrap();
+        assert_eq!(
+            String::from_utf8(table.writer).unwrap(),
+            "target,reference,size,target_mtime,reference_mtime,action,status\r\n\
+             \"t/a,\"\"b\"\"\tc\",r\\\\a,7,1970-01-01T00:00:00Z,,hardlink,done\r\n"
+        );
+
+        let mut table = TableWriter::new(Vec::new(), Separator::Tab).unwrap();
+        table.write(&record).unwrap();
+        assert_eq!(
+            String::from_utf8(table.writer).unwrap(),
+            "target\treference\tsize\ttarget_mtime\treference_mtime\taction\tstatus\n\
+             t/a,\"b\"\\tc\tr\\\\a\t7\t1970-01-01T00:00:00Z\t\thardlink\tdone\n"
+        );
+    }
+}
@@ -0,0 +1,267 @@
+//! Moving files to the trash as described by the
+//! [FreeDesktop.org Trash specification](https://specifications.freedesktop.org/trash-spec/latest/)
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Moves a file to the trash
+///
+/// The home trash is used for files on the same filesystem as the home
+/// directory, and the trash in the top directory of the file's mount
+/// point for files on other filesystems.
+///
+/// `before` is called with the path the file is moved to right before it
+/// is moved, and the file is not moved if it fails.
+///
+/// Returns the path the file was moved to.
+#[cfg(unix)]
+pub fn trash(path: &Path, before: impl FnOnce(&Path) -> io::Result<()>) -> io::Result<PathBuf> {
+    let data_home = match std::env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => dir.into(),
+        _ => {
+            let home = std::env::var_os("HOME")
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "HOME is not set"))?;
+            Path::new(&home).join(".local").join("share")
+        }
+    };
+    unix::trash_file(path, &data_home.join("Trash"), before)
+}
+
+#[cfg(not(unix))]
+pub fn trash(_path: &Path, _before: impl FnOnce(&Path) -> io::Result<()>) -> io::Result<PathBuf> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "trash is only supported on Unix",
+    ))
+}
+
+/// Returns a path to the info file of a file moved to the trash
+pub fn trash_info_path(trashed: &Path) -> PathBuf {
+    let trash_dir = trashed.parent().unwrap().parent().unwrap();
+    let mut info_name = trashed.file_name().unwrap().to_owned();
+    info_name.push(".trashinfo");
+    trash_dir.join("info").join(info_name)
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::ffi::OsString;
+    use std::fs::{self, DirBuilder, File};
+    use std::io::{self, Write};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+    use std::path::{Path, PathBuf};
+    use std::time::SystemTime;
+
+    /// Moves a file to the trash, using `home_trash` for files on its filesystem
+    pub fn trash_file(
+        path: &Path,
+        home_trash: &Path,
+        before: impl FnOnce(&Path) -> io::Result<()>,
+    ) -> io::Result<PathBuf> {
+        let path = std::path::absolute(path)?;
+        let path = path
+            .parent()
+            .unwrap()
+            .canonicalize()?
+            .join(path.file_name().unwrap());
+        let dev = path.symlink_metadata()?.dev();
+
+        create_dir(home_trash)?;
+        let trash_dir = if home_trash.metadata()?.dev() == dev {
+            home_trash.to_owned()
+        } else {
+            topdir_trash(&mount_point(&path, dev)?)?
+        };
+        let files_dir = trash_dir.join("files");
+        let info_dir = trash_dir.join("info");
+        create_dir(&files_dir)?;
+        create_dir(&info_dir)?;
+
+        let (name, mut info) = reserve_name(&files_dir, &info_dir, path.file_name().unwrap())?;
+        let info_path = info_dir.join(trashinfo_name(&name));
+        let result = write!(
+            info,
+            "[Trash Info]\nPath={}\nDeletionDate={}\n",
+            percent_encode(path.as_os_str().as_bytes()),
+            deletion_date(SystemTime::now())?,
+        )
+        .and_then(|()| before(&files_dir.join(&name)))
+        .and_then(|()| fs::rename(&path, files_dir.join(&name)));
+        if let Err(e) = result {
+            let _ = fs::remove_file(files_dir.join(&name));
+            let _ = fs::remove_file(info_path);
+            return Err(e);
+        }
+        Ok(files_dir.join(name))
+    }
+
+    /// Creates a directory accessible by the current user only, if it does not exist
+    fn create_dir(path: &Path) -> io::Result<()> {
+        DirBuilder::new().recursive(true).mode(0o700).create(path)
+    }
+
+    /// Returns the top directory of the filesystem with device ID `dev` containing `path`
+    fn mount_point(path: &Path, dev: u64) -> io::Result<PathBuf> {
+        let mut topdir = path;
+        while let Some(parent) = topdir.parent() {
+            if parent.metadata()?.dev() != dev {
+                break;
+            }
+            topdir = parent;
+        }
+        Ok(topdir.to_owned())
+    }
+
+    /// Returns the trash directory of the current user in a top directory
+    fn topdir_trash(topdir: &Path) -> io::Result<PathBuf> {
+        const STICKY_BIT: u32 = 0o1000;
+        let uid = unsafe { libc::getuid() };
+        let shared = topdir.join(".Trash");
+        if let Ok(meta) = shared.symlink_metadata() {
+            if meta.is_dir() && meta.mode() & STICKY_BIT != 0 {
+                let trash_dir = shared.join(uid.to_string());
+                if create_dir(&trash_dir).is_ok() {
+                    return Ok(trash_dir);
+                }
+            }
+        }
+        let trash_dir = topdir.join(format!(".Trash-{uid}"));
+        create_dir(&trash_dir)?;
+        Ok(trash_dir)
+    }
+
+    fn trashinfo_name(name: &OsString) -> OsString {
+        let mut info_name = name.clone();
+        info_name.push(".trashinfo");
+        info_name
+    }
+
+    /// Reserves a name in the trash by atomically creating its info file and
+    /// an empty file in `files_dir`, which the trashed file is renamed over
+    ///
+    /// A name is only taken if neither of the files exists yet.
+    fn reserve_name(
+        files_dir: &Path,
+        info_dir: &Path,
+        file_name: &std::ffi::OsStr,
+    ) -> io::Result<(OsString, File)> {
+        let create_new = |path: PathBuf| File::options().write(true).create_new(true).open(path);
+        let mut index = 1;
+        let mut name = file_name.to_owned();
+        loop {
+            let info_path = info_dir.join(trashinfo_name(&name));
+            let result = create_new(info_path.clone()).and_then(|info| {
+                create_new(files_dir.join(&name))
+                    .inspect_err(|_| {
+                        let _ = fs::remove_file(&info_path);
+                    })
+                    .map(|_| info)
+            });
+            match result {
+                Ok(info) => return Ok((name, info)),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    index += 1;
+                    name = file_name.to_owned();
+                    name.push(format!(".{index}"));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Encodes a path as an URL path
+    fn percent_encode(bytes: &[u8]) -> String {
+        let mut encoded = String::with_capacity(bytes.len());
+        for &byte in bytes {
+            if byte.is_ascii_alphanumeric() || b"/-_.~".contains(&byte) {
+                encoded.push(byte as char);
+            } else {
+                encoded.push_str(&format!("%{byte:02X}"));
+            }
+        }
+        encoded
+    }
+
+    /// Formats a time as `YYYY-MM-DDThh:mm:ss` in the local time zone
+    fn deletion_date(time: SystemTime) -> io::Result<String> {
+        let secs = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_err(io::Error::other)?
+            .as_secs() as libc::time_t;
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        if unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            tm.tm_year + 1900,
+            tm.tm_mon + 1,
+            tm.tm_mday,
+            tm.tm_hour,
+            tm.tm_min,
+            tm.tm_sec
+        ))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use tempdir::TempDir;
+
+        #[test]
+        fn test_trash_file() {
+            let tmp = TempDir::new("test_trash_file").unwrap();
+            let tmp_path = tmp.path().canonicalize().unwrap();
+            let home_trash = tmp_path.join("Trash");
+            let file = tmp_path.join("a file");
+            fs::write(&file, "content").unwrap();
+            fs::create_dir_all(home_trash.join("info")).unwrap();
+            fs::write(home_trash.join("info").join("a file.trashinfo"), "").unwrap();
+
+            let mut announced = None;
+            let trashed = trash_file(&file, &home_trash, |path| {
+                assert!(file.exists());
+                announced = Some(path.to_owned());
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(announced.as_ref(), Some(&trashed));
+            assert!(!file.exists());
+            assert_eq!(trashed, home_trash.join("files").join("a file.2"));
+            assert!(crate::trash::trash_info_path(&trashed).exists());
+            assert_eq!(
+                fs::read_to_string(home_trash.join("files").join("a file.2")).unwrap(),
+                "content"
+            );
+            let info =
+                fs::read_to_string(home_trash.join("info").join("a file.2.trashinfo")).unwrap();
+            let expected = format!(
+                "[Trash Info]\nPath={}\nDeletionDate=",
+                percent_encode(file.as_os_str().as_bytes())
+            );
+            assert!(info.starts_with(&expected), "{info}");
+            assert!(info.contains("%20file"));
+        }
+
+        #[test]
+        fn test_trash_orphaned_file() {
+            let tmp = TempDir::new("test_trash_orphaned_file").unwrap();
+            let tmp_path = tmp.path().canonicalize().unwrap();
+            let home_trash = tmp_path.join("Trash");
+            let file = tmp_path.join("f");
+            fs::write(&file, "dup").unwrap();
+            fs::create_dir_all(home_trash.join("files")).unwrap();
+            fs::write(home_trash.join("files").join("f"), "precious").unwrap();
+
+            let trashed = trash_file(&file, &home_trash, |_| Ok(())).unwrap();
+            assert_eq!(trashed, home_trash.join("files").join("f.2"));
+            assert_eq!(fs::read_to_string(&trashed).unwrap(), "dup");
+            assert_eq!(
+                fs::read_to_string(home_trash.join("files").join("f")).unwrap(),
+                "precious"
+            );
+            assert!(!home_trash.join("info").join("f.trashinfo").exists());
+        }
+    }
+}